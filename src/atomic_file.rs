@@ -0,0 +1,159 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Suffix of the temporary files messages are written to before being renamed into place.
+/// One left behind by a crash holds an incomplete message and can be deleted.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Writes `body` to `path` so that `path` either keeps its previous state or holds all of
+/// `body`, even if the process or machine dies halfway: the data goes to a temporary file in
+/// the same directory, is flushed to disk and then renamed over `path`. `modified` becomes
+/// the file's modification time once it is in place; the temporary file keeps the time it
+/// was written at, so it can be told apart from one abandoned by a crash.
+pub fn write(path: &Path, body: &[u8], modified: Option<SystemTime>) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let result = write_temp(&temp_path, body).and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    if let Some(modified) = modified {
+        let file = File::options().write(true).open(path)?;
+        file.set_modified(modified)?;
+        file.sync_all()?;
+    }
+    sync_parent(path)
+}
+
+/// Whether `path` is a temporary file of `write`.
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_SUFFIX))
+}
+
+fn write_temp(temp_path: &Path, body: &[u8]) -> io::Result<()> {
+    let mut file = File::create(temp_path)?;
+    file.write_all(body)?;
+    file.sync_all()
+}
+
+/// `.<name>.<pid>-<n>.tmp` next to `path`, hidden and unique per write so accounts fetching
+/// the same blob at the same time don't write to the same file.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_SUFFIX
+    ))
+}
+
+/// Flushes the directory entry of the rename, so the file survives a power loss too.
+/// Directories can't be opened for syncing on every platform, which is not an error.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    match File::open(dir) {
+        Ok(dir) => dir.sync_all().or(Ok(())),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// An empty directory of its own for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "courrier-atomic-file-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn writes_and_replaces_without_leftovers() {
+        let dir = test_dir("replace");
+        let path = dir.join("1.eml");
+
+        write(&path, b"first", None).unwrap();
+        write(&path, b"second", None).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(entries(&dir), ["1.eml"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sets_the_modification_time() {
+        let dir = test_dir("modified");
+        let path = dir.join("1.eml");
+        let internal_date = UNIX_EPOCH + Duration::from_secs(837_596_665);
+
+        write(&path, b"body", Some(internal_date)).unwrap();
+
+        assert_eq!(
+            fs::metadata(&path).unwrap().modified().unwrap(),
+            internal_date
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_rename_keeps_the_target_and_removes_the_temp_file() {
+        let dir = test_dir("failed");
+        // A non-empty directory can't be replaced by a file
+        let path = dir.join("1.eml");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("keep"), b"").unwrap();
+
+        assert!(write(&path, b"body", None).is_err());
+
+        assert!(path.join("keep").exists());
+        assert_eq!(entries(&dir), ["1.eml"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_files_are_hidden_unique_and_recognized() {
+        let path = Path::new("/archive/INBOX/1.eml");
+        let first = temp_path(path);
+        let second = temp_path(path);
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        assert!(first
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".1.eml."));
+        assert!(is_temp_file(&first));
+        assert!(!is_temp_file(path));
+        assert!(!is_temp_file(Path::new("/archive/INBOX/notes.tmp")));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::atomic_file;
+use crate::database::Database;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Directory under `email_storage_path` holding the content-addressed messages. Account
+/// directories are named after email addresses, so they can't collide with it.
+const BLOB_DIR: &str = "blobs";
+
+/// Content-addressed message store: every distinct raw message is kept once, as
+/// `blobs/<first two hex digits>/<sha256>.eml`, no matter how many mailboxes or accounts
+/// it was fetched from.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+/// Where a message ended up in the blob store.
+pub struct Blob {
+    pub sha256: String,
+    pub path: PathBuf,
+    /// False if an identical message was already stored
+    pub created: bool,
+}
+
+/// Outcome of `dedupe_archive`.
+#[derive(Debug, Default)]
+pub struct DedupeReport {
+    pub files_scanned: usize,
+    pub blobs_created: usize,
+    pub duplicates_removed: usize,
+    pub bytes_saved: u64,
+    pub missing_files: usize,
+}
+
+impl BlobStore {
+    pub fn new(output_dir: &Path) -> Self {
+        BlobStore {
+            root: output_dir.join(BLOB_DIR),
+        }
+    }
+
+    pub fn contains_path(&self, path: &Path) -> bool {
+        path.starts_with(&self.root)
+    }
+
+    fn path_for(&self, sha256: &str) -> PathBuf {
+        self.root.join(&sha256[..2]).join(format!("{}.eml", sha256))
+    }
+
+    /// Writes `body` to the store unless an identical message is already there. `modified`
+    /// only applies to a new blob.
+    pub fn store(&self, body: &[u8], modified: Option<SystemTime>) -> Result<Blob> {
+        let sha256 = sha256_hex(body);
+        let path = self.path_for(&sha256);
+        if path.exists() {
+            return Ok(Blob {
+                sha256,
+                path,
+                created: false,
+            });
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        atomic_file::write(&path, body, modified)
+            .map_err(|e| anyhow::anyhow!("Failed to save {}: {:?}", path.display(), e))?;
+        Ok(Blob {
+            sha256,
+            path,
+            created: true,
+        })
+    }
+
+    /// Stores the content of an already archived message file, like `store`. A blob that
+    /// is already there is only kept if it really holds `body`, otherwise (e.g. truncated by
+    /// a crash) it is written again. The file itself is left for the caller to remove once
+    /// the database points at the blob.
+    fn adopt(&self, body: &[u8], modified: Option<SystemTime>) -> Result<Blob> {
+        let sha256 = sha256_hex(body);
+        let path = self.path_for(&sha256);
+        match fs::read(&path) {
+            Ok(existing) if existing == body => {
+                return Ok(Blob {
+                    sha256,
+                    path,
+                    created: false,
+                });
+            }
+            Ok(_) => warn!(path = %path.display(), "Blob doesn't match its name, writing it again"),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        atomic_file::write(&path, body, modified)
+            .map_err(|e| anyhow::anyhow!("Failed to save {}: {:?}", path.display(), e))?;
+        Ok(Blob {
+            sha256,
+            path,
+            created: true,
+        })
+    }
+}
+
+pub fn sha256_hex(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// Moves every archived message that isn't in the blob store yet into it, pointing the
+/// database rows at the blobs. Duplicate copies are deleted and counted as saved space.
+/// Files are copied rather than renamed, so the original stays in place until its rows
+/// point at the blob.
+pub fn dedupe_archive(db: &Database, output_dir: &Path) -> Result<DedupeReport> {
+    let store = BlobStore::new(output_dir);
+    let mut report = DedupeReport::default();
+
+    for file_path in db.get_archived_file_paths()? {
+        let file = PathBuf::from(&file_path);
+        if store.contains_path(&file) {
+            continue;
+        }
+        report.files_scanned += 1;
+
+        let body = match fs::read(&file) {
+            Ok(body) => body,
+            Err(_) => {
+                warn!(path = %file.display(), "Missing file, skipping");
+                report.missing_files += 1;
+                continue;
+            }
+        };
+
+        // The file is only removed once the blob is on disk and the rows point at it, so an
+        // error at any step leaves the archive as it was
+        let modified = fs::metadata(&file).and_then(|m| m.modified()).ok();
+        let blob = store
+            .adopt(&body, modified)
+            .with_context(|| format!("Failed to copy {} into the blob store", file.display()))?;
+        db.point_files_to_blob(&file_path, &blob.sha256, &blob.path, body.len())?;
+        fs::remove_file(&file).with_context(|| format!("Failed to remove {}", file.display()))?;
+        if blob.created {
+            report.blobs_created += 1;
+        } else {
+            report.duplicates_removed += 1;
+            report.bytes_saved += body.len() as u64;
+        }
+
+        // Drop mailbox directories that are empty now, fails harmlessly for the others
+        if let Some(dir) = file.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{FetchedMessage, MessageAttributes, TestDb};
+
+    /// An empty archive directory of its own for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "courrier-blob-store-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("me@example.com").join("INBOX")).unwrap();
+        dir
+    }
+
+    /// Writes `body` as message `uid` of the INBOX and records it.
+    fn archive(db: &Database, dir: &Path, uid: u32, body: &[u8]) -> PathBuf {
+        let path = dir
+            .join("me@example.com")
+            .join("INBOX")
+            .join(format!("{}.eml", uid));
+        fs::write(&path, body).unwrap();
+        let message = FetchedMessage {
+            uid,
+            file_path: &path,
+            size_bytes: body.len(),
+            sha256: None,
+            attributes: &MessageAttributes::default(),
+            gmail: None,
+        };
+        db.record_fetched_messages("me@example.com", "INBOX", 1, &[message])
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn moves_new_messages_into_the_store() {
+        let test_db = TestDb::new("blob-new");
+        let db = test_db.open();
+        let dir = test_dir("new");
+        let file = archive(&db, &dir, 1, b"Subject: one\r\n\r\nbody\r\n");
+
+        let report = dedupe_archive(&db, &dir).unwrap();
+        assert_eq!(report.blobs_created, 1);
+        assert_eq!(report.duplicates_removed, 0);
+        assert!(!file.exists());
+        let paths = db.get_archived_file_paths().unwrap();
+        assert_eq!(paths.len(), 1);
+        let blob = PathBuf::from(&paths[0]);
+        assert!(BlobStore::new(&dir).contains_path(&blob));
+        assert_eq!(fs::read(&blob).unwrap(), b"Subject: one\r\n\r\nbody\r\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removes_duplicates_and_rewrites_a_damaged_blob() {
+        let test_db = TestDb::new("blob-duplicate");
+        let db = test_db.open();
+        let dir = test_dir("duplicate");
+        let body = b"Subject: same\r\n\r\nbody\r\n";
+        let first = archive(&db, &dir, 1, body);
+        let second = archive(&db, &dir, 2, body);
+
+        // A blob with the right name but the wrong content isn't taken for a copy
+        let store = BlobStore::new(&dir);
+        let blob = store.path_for(&sha256_hex(body));
+        fs::create_dir_all(blob.parent().unwrap()).unwrap();
+        fs::write(&blob, b"Subject: sa").unwrap();
+
+        let report = dedupe_archive(&db, &dir).unwrap();
+        assert_eq!(report.blobs_created, 1);
+        assert_eq!(report.duplicates_removed, 1);
+        assert_eq!(report.bytes_saved, body.len() as u64);
+        assert!(!first.exists() && !second.exists());
+        assert_eq!(fs::read(&blob).unwrap(), body);
+        assert_eq!(
+            db.get_archived_file_paths().unwrap(),
+            [blob.to_string_lossy().to_string()]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_the_file_when_the_database_update_fails() {
+        let test_db = TestDb::new("blob-db-failure");
+        let db = test_db.open();
+        let dir = test_dir("db-failure");
+        let file = archive(&db, &dir, 1, b"Subject: kept\r\n\r\nbody\r\n");
+
+        // Makes `point_files_to_blob` fail after the blob is written
+        rusqlite::Connection::open(&test_db.0)
+            .unwrap()
+            .execute("DROP TABLE gmail_messages", [])
+            .unwrap();
+
+        assert!(dedupe_archive(&db, &dir).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"Subject: kept\r\n\r\nbody\r\n");
+        assert_eq!(
+            db.get_archived_file_paths().unwrap(),
+            [file.to_string_lossy().to_string()]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,36 +1,291 @@
-use anyhow::Result;
+use crate::mailbox_filter::MailboxFilter;
+use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// IMAP LOGIN with `password`
+    #[default]
+    Password,
+    /// SASL XOAUTH2/OAUTHBEARER with an access token obtained from `refresh_token`
+    OAuth2,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SaslMechanism {
+    #[default]
+    XOAuth2,
+    OAuthBearer,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Implicit TLS, usually on port 993
+    #[default]
+    Tls,
+    /// Plaintext connection upgraded with STARTTLS, usually on port 143
+    StartTls,
+    /// Unencrypted connection, only allowed with `allow_insecure = true`
+    None,
+}
+
+/// What happens to archived messages once they are gone from the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeletionPolicy {
+    /// Keep the local copy, the archive is a backup
+    #[default]
+    Keep,
+    /// Move the local copy to the trash area under `email_storage_path`
+    Mirror,
+    /// Keep the local copy for the given period, then delete it
+    PurgeAfter(RetentionPeriod),
+}
+
+/// A period such as `90d`, `12h` or `2w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RetentionPeriod(pub chrono::Duration);
+
+impl TryFrom<String> for RetentionPeriod {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid period {:?}, expected e.g. \"90d\"", value);
+        let unit_at = value
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let amount: i64 = value[..unit_at].parse().map_err(|_| invalid())?;
+        let duration = match &value[unit_at..] {
+            "m" => chrono::Duration::try_minutes(amount),
+            "h" => chrono::Duration::try_hours(amount),
+            "d" => chrono::Duration::try_days(amount),
+            "w" => chrono::Duration::try_weeks(amount),
+            _ => None,
+        };
+        duration.map(RetentionPeriod).ok_or_else(invalid)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Plain,
+    /// One JSON object per event, including the fields of its spans
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// The `[logging]` table. `COURRIER_LOG` and `COURRIER_LOG_FORMAT` override `level` and
+/// `format`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    /// A level (`info`, `debug`, ...) or a filter such as `courrier=debug,warn`
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Also write logs to this file, rotated as configured
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Rotated files to keep, all of them if unset
+    pub max_files: Option<usize>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_log_level(),
+            format: LogFormat::default(),
+            file: None,
+            rotation: LogRotation::default(),
+            max_files: None,
+        }
+    }
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+/// How often, and how patiently, one kind of IMAP operation is retried after a network
+/// error. Each retry waits twice as long as the previous one (up to `max_backoff_ms`), minus
+/// a random jitter of up to half the delay.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+/// The `[retry]` table, one policy per operation.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Opening the TCP/TLS connection
+    pub connect: RetryPolicy,
+    /// Logging in, over a new connection for each attempt
+    pub login: RetryPolicy,
+    /// Selecting a mailbox, reconnecting before each retry
+    pub select: RetryPolicy,
+    /// Downloading a batch or message, reconnecting and reselecting the mailbox before each
+    /// retry
+    pub fetch: RetryPolicy,
+}
+
+/// Per-server TLS settings, used for both implicit TLS and STARTTLS connections.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TlsOptions {
+    /// PEM file with extra CA certificates to trust (e.g. an internal CA)
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS, used together with `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM (PKCS#8) private key matching `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server's certificate. When set, the certificate is trusted
+    /// if and only if it matches, regardless of the CA chain.
+    pub pin_sha256: Option<String>,
+    /// Skip certificate and hostname verification entirely. For lab use only.
+    pub danger_accept_invalid_certs: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuth2Config {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub refresh_token: String,
+    /// Token endpoint, defaults to Google's or Microsoft's depending on the IMAP host
+    pub token_url: Option<String>,
+    pub mechanism: SaslMechanism,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AccountConfig {
     pub email: String,
     pub username: String,
     pub password: String,
+    pub auth: AuthMethod,
+    pub oauth2: Option<OAuth2Config>,
     pub server: String,
     pub port: u16,
+    pub security: Security,
+    pub tls: TlsOptions,
+    pub idle_mailboxes: Vec<String>,
+    pub include_mailboxes: Vec<String>,
+    pub exclude_mailboxes: Vec<String>,
+    pub fetch_batch_size: usize,
+    pub fetch_batch_max_bytes: u64,
+    pub max_connections: Option<usize>,
+    pub blob_store: bool,
+    pub deletion_policy: DeletionPolicy,
+    pub retry: RetryConfig,
+}
+
+#[cfg(test)]
+impl AccountConfig {
+    /// A password account on `imap.example.com` with default settings.
+    pub fn for_test(email: &str) -> Self {
+        AccountConfig {
+            email: email.to_string(),
+            username: email.to_string(),
+            password: "password".to_string(),
+            auth: AuthMethod::Password,
+            oauth2: None,
+            server: "imap.example.com".to_string(),
+            port: 993,
+            security: Security::Tls,
+            tls: TlsOptions::default(),
+            idle_mailboxes: Vec::new(),
+            include_mailboxes: Vec::new(),
+            exclude_mailboxes: Vec::new(),
+            fetch_batch_size: 10,
+            fetch_batch_max_bytes: 0,
+            max_connections: None,
+            blob_store: false,
+            deletion_policy: DeletionPolicy::default(),
+            retry: RetryConfig::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct Account {
     email: String,
     username: String,
+    #[serde(default)]
     password: String,
+    #[serde(default)]
+    auth: AuthMethod,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
+    token_url: Option<String>,
+    #[serde(default)]
+    sasl_mechanism: SaslMechanism,
+    idle_mailboxes: Option<Vec<String>>,
+    include_mailboxes: Option<Vec<String>>,
+    exclude_mailboxes: Option<Vec<String>>,
+    deletion_policy: Option<DeletionPolicy>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct ServerConfig {
     host: String,
-    #[serde(default = "default_port")]
-    port: u16,
+    port: Option<u16>,
+    #[serde(default)]
+    security: Security,
+    #[serde(default)]
+    allow_insecure: bool,
+    tls_ca_file: Option<PathBuf>,
+    tls_client_cert: Option<PathBuf>,
+    tls_client_key: Option<PathBuf>,
+    tls_pin_sha256: Option<String>,
+    #[serde(default)]
+    danger_accept_invalid_certs: bool,
+    #[serde(default)]
+    idle_mailboxes: Vec<String>,
+    #[serde(default)]
+    include_mailboxes: Vec<String>,
+    #[serde(default)]
+    exclude_mailboxes: Vec<String>,
+    #[serde(default)]
+    deletion_policy: DeletionPolicy,
+    fetch_batch_size: Option<usize>,
+    fetch_batch_max_bytes: Option<u64>,
+    max_connections: Option<usize>,
     accounts: Vec<Account>,
 }
 
-fn default_port() -> u16 {
-    993
-}
-
 fn default_email_storage_path() -> String {
     "emails".to_string()
 }
@@ -39,6 +294,23 @@ fn default_fetch_on_startup() -> bool {
     true
 }
 
+fn default_fetch_batch_size() -> usize {
+    500
+}
+
+fn default_fetch_batch_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_concurrent_accounts() -> usize {
+    1
+}
+
+fn default_idle_keepalive_seconds() -> u64 {
+    // RFC 2177 servers may drop clients idling for 30 minutes, re-issue IDLE well before that
+    25 * 60
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfig {
     #[serde(default = "default_email_storage_path")]
@@ -46,6 +318,20 @@ pub struct AppConfig {
     pub fetch_interval_seconds: Option<u64>,
     #[serde(default = "default_fetch_on_startup")]
     pub fetch_on_startup: bool,
+    #[serde(default = "default_idle_keepalive_seconds")]
+    pub idle_keepalive_seconds: u64,
+    #[serde(default = "default_fetch_batch_size")]
+    pub fetch_batch_size: usize,
+    #[serde(default = "default_fetch_batch_max_bytes")]
+    pub fetch_batch_max_bytes: u64,
+    #[serde(default = "default_max_concurrent_accounts")]
+    pub max_concurrent_accounts: usize,
+    #[serde(default)]
+    pub blob_store: bool,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
     pub(self) servers: Vec<ServerConfig>,
 }
 
@@ -56,19 +342,122 @@ struct Config {
     fetch_interval_seconds: Option<u64>,
     #[serde(default = "default_fetch_on_startup")]
     fetch_on_startup: bool,
+    #[serde(default = "default_idle_keepalive_seconds")]
+    idle_keepalive_seconds: u64,
+    #[serde(default = "default_fetch_batch_size")]
+    fetch_batch_size: usize,
+    #[serde(default = "default_fetch_batch_max_bytes")]
+    fetch_batch_max_bytes: u64,
+    #[serde(default = "default_max_concurrent_accounts")]
+    max_concurrent_accounts: usize,
+    #[serde(default)]
+    blob_store: bool,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    retry: RetryConfig,
     servers: Vec<ServerConfig>,
 }
 
 pub fn load_config_from_file(config_path: &PathBuf) -> Result<AppConfig> {
     let config_content = fs::read_to_string(config_path)?;
-    let config: Config = toml::from_str(&config_content)?;
+    parse_config(&config_content)
+}
+
+/// Parses and validates the contents of a config file.
+fn parse_config(config_content: &str) -> Result<AppConfig> {
+    let config: Config = toml::from_str(config_content)?;
 
-    Ok(AppConfig {
+    for server in &config.servers {
+        if server.security == Security::None && !server.allow_insecure {
+            return Err(anyhow::anyhow!(
+                "Server {} uses security = \"none\", which sends credentials in plaintext.\n\
+                Set allow_insecure = true on this server to confirm.",
+                server.host
+            ));
+        }
+        if server.tls_client_cert.is_some() != server.tls_client_key.is_some() {
+            return Err(anyhow::anyhow!(
+                "Server {} must set both tls_client_cert and tls_client_key, or neither",
+                server.host
+            ));
+        }
+        for account in &server.accounts {
+            match account.auth {
+                AuthMethod::Password if account.password.is_empty() => {
+                    return Err(anyhow::anyhow!(
+                        "Account {} has no password.\n\
+                        Set password, or auth = \"oauth2\" with client_id and refresh_token.",
+                        account.email
+                    ));
+                }
+                AuthMethod::OAuth2
+                    if account.client_id.is_none() || account.refresh_token.is_none() =>
+                {
+                    return Err(anyhow::anyhow!(
+                        "Account {} uses auth = \"oauth2\", which needs both client_id and \
+                        refresh_token",
+                        account.email
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let app_config = AppConfig {
         email_storage_path: config.email_storage_path,
         fetch_interval_seconds: config.fetch_interval_seconds,
         fetch_on_startup: config.fetch_on_startup,
+        idle_keepalive_seconds: config.idle_keepalive_seconds,
+        fetch_batch_size: config.fetch_batch_size,
+        fetch_batch_max_bytes: config.fetch_batch_max_bytes,
+        max_concurrent_accounts: config.max_concurrent_accounts,
+        blob_store: config.blob_store,
+        logging: config.logging,
+        retry: config.retry,
         servers: config.servers,
-    })
+    };
+
+    // Reject bad mailbox patterns at startup rather than on the first fetch
+    let accounts = extract_accounts(&app_config);
+    for account in &accounts {
+        MailboxFilter::new(&account.include_mailboxes, &account.exclude_mailboxes)
+            .with_context(|| format!("Invalid mailbox filter for {}", account.email))?;
+    }
+
+    // Every connection to a server counts against the same `max_connections`, including the
+    // ones IDLE watchers keep open for good, which must leave at least one to fetch with
+    let mut servers: BTreeMap<String, (Option<usize>, usize)> = BTreeMap::new();
+    for account in &accounts {
+        let server = format!("{}:{}", account.server, account.port);
+        let (max_connections, idle_watchers) = servers
+            .entry(server.clone())
+            .or_insert((account.max_connections, 0));
+        if *max_connections != account.max_connections {
+            return Err(anyhow::anyhow!(
+                "Server {} is listed more than once with different max_connections",
+                server
+            ));
+        }
+        *idle_watchers += account.idle_mailboxes.len();
+    }
+    for (server, (max_connections, idle_watchers)) in servers {
+        if let Some(max_connections) = max_connections {
+            if idle_watchers >= max_connections.max(1) {
+                return Err(anyhow::anyhow!(
+                    "Server {} allows max_connections = {}, but has {} IDLE watchers, which \
+                    would leave no connection to fetch with.\n\
+                    Raise max_connections or watch fewer idle_mailboxes.",
+                    server,
+                    max_connections,
+                    idle_watchers
+                ));
+            }
+        }
+    }
+
+    Ok(app_config)
 }
 
 pub fn load_config() -> Result<AppConfig> {
@@ -111,11 +500,148 @@ pub fn extract_accounts(config: &AppConfig) -> Vec<AccountConfig> {
                 email: account.email.clone(),
                 username: account.username.clone(),
                 password: account.password.clone(),
+                auth: account.auth,
+                oauth2: match (&account.client_id, &account.refresh_token) {
+                    (Some(client_id), Some(refresh_token)) => Some(OAuth2Config {
+                        client_id: client_id.clone(),
+                        client_secret: account.client_secret.clone(),
+                        refresh_token: refresh_token.clone(),
+                        token_url: account.token_url.clone(),
+                        mechanism: account.sasl_mechanism,
+                    }),
+                    _ => None,
+                },
                 server: server.host.clone(),
-                port: server.port,
+                port: server.port.unwrap_or(match server.security {
+                    Security::Tls => 993,
+                    Security::StartTls | Security::None => 143,
+                }),
+                security: server.security,
+                tls: TlsOptions {
+                    ca_file: server.tls_ca_file.clone(),
+                    client_cert: server.tls_client_cert.clone(),
+                    client_key: server.tls_client_key.clone(),
+                    pin_sha256: server.tls_pin_sha256.clone(),
+                    danger_accept_invalid_certs: server.danger_accept_invalid_certs,
+                },
+                idle_mailboxes: account
+                    .idle_mailboxes
+                    .clone()
+                    .unwrap_or_else(|| server.idle_mailboxes.clone()),
+                include_mailboxes: account
+                    .include_mailboxes
+                    .clone()
+                    .unwrap_or_else(|| server.include_mailboxes.clone()),
+                exclude_mailboxes: account
+                    .exclude_mailboxes
+                    .clone()
+                    .unwrap_or_else(|| server.exclude_mailboxes.clone()),
+                fetch_batch_size: server.fetch_batch_size.unwrap_or(config.fetch_batch_size),
+                fetch_batch_max_bytes: server
+                    .fetch_batch_max_bytes
+                    .unwrap_or(config.fetch_batch_max_bytes),
+                max_connections: server.max_connections,
+                blob_store: config.blob_store,
+                deletion_policy: account.deletion_policy.unwrap_or(server.deletion_policy),
+                retry: config.retry,
             });
         }
     }
 
     accounts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(accounts: &str) -> Result<AppConfig> {
+        parse_config(&format!(
+            "email_storage_path = \"emails\"\n\n\
+             [[servers]]\n\
+             host = \"imap.example.com\"\n\
+             accounts = [{}]\n",
+            accounts
+        ))
+    }
+
+    #[test]
+    fn accepts_password_and_oauth2_accounts() {
+        let config = load(
+            r#"
+            { email = "a@example.com", username = "a", password = "secret" },
+            { email = "b@example.com", username = "b", auth = "oauth2", client_id = "client", refresh_token = "refresh" },
+            "#,
+        )
+        .unwrap();
+        let accounts = extract_accounts(&config);
+        assert!(accounts[0].oauth2.is_none());
+        let oauth2 = accounts[1].oauth2.as_ref().unwrap();
+        assert_eq!(oauth2.client_id, "client");
+        assert_eq!(oauth2.refresh_token, "refresh");
+    }
+
+    #[test]
+    fn rejects_password_account_without_password() {
+        let error = load(r#"{ email = "a@example.com", username = "a" }"#).unwrap_err();
+        assert!(error.to_string().contains("a@example.com has no password"));
+    }
+
+    #[test]
+    fn rejects_oauth2_account_without_refresh_token() {
+        let error = load(
+            r#"{ email = "b@example.com", username = "b", auth = "oauth2", client_id = "c" }"#,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("b@example.com uses auth = \"oauth2\""));
+    }
+
+    #[test]
+    fn parses_retention_periods() {
+        let period = |value: &str| RetentionPeriod::try_from(value.to_string());
+        assert_eq!(
+            period("90d"),
+            Ok(RetentionPeriod(chrono::Duration::days(90)))
+        );
+        assert_eq!(
+            period("12h"),
+            Ok(RetentionPeriod(chrono::Duration::hours(12)))
+        );
+        assert_eq!(
+            period("30m"),
+            Ok(RetentionPeriod(chrono::Duration::minutes(30)))
+        );
+        assert_eq!(
+            period("2w"),
+            Ok(RetentionPeriod(chrono::Duration::weeks(2)))
+        );
+
+        for invalid in ["", "90", "d", "90y", "-1d", "1.5d", "90 d"] {
+            assert!(period(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_servers_without_a_connection_left_to_fetch_with() {
+        let server = |max_connections: usize| {
+            format!(
+                "[[servers]]\n\
+                 host = \"imap.example.com\"\n\
+                 max_connections = {}\n\
+                 idle_mailboxes = [\"INBOX\"]\n\
+                 accounts = [{{ email = \"a{}@example.com\", username = \"a\", password = \"p\" }}]\n",
+                max_connections, max_connections
+            )
+        };
+
+        assert!(parse_config(&server(2)).is_ok());
+        let error = parse_config(&server(1)).unwrap_err();
+        assert!(error.to_string().contains("has 1 IDLE watchers"));
+
+        // Both entries are the same server, and share one cap
+        let error = parse_config(&format!("{}\n{}", server(2), server(3))).unwrap_err();
+        assert!(error.to_string().contains("different max_connections"));
+    }
+}
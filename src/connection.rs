@@ -0,0 +1,489 @@
+use crate::config::{AccountConfig, Security, TlsOptions};
+use anyhow::{Context, Result};
+use imap::extensions::idle::SetReadTimeout;
+use imap::{Client, Session};
+use imap_proto::{RequestId, Response, Status};
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// How long opening the TCP connection to each of the server's addresses may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a read or write may block before the connection counts as dead. A connection
+/// dropped without a reset (NAT timeout, lost Wi-Fi) would otherwise hang forever; this way it
+/// fails with a network error that is retried over a new connection.
+const IO_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The transport under an IMAP session: implicit TLS, TLS upgraded via STARTTLS, or plaintext.
+#[derive(Debug)]
+enum Transport {
+    Tls(Box<TlsStream<TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.read(buf),
+            Transport::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.write(buf),
+            Transport::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tls(stream) => stream.flush(),
+            Transport::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    transport: Transport,
+    /// Bytes a `RawChannel` read past the end of its response, served to the session first
+    pending: Vec<u8>,
+    raw_tag: u32,
+}
+
+/// The stream an `ImapSession` runs on. The connection is shared with the session's
+/// `RawChannel`, which the session never uses concurrently.
+#[derive(Debug)]
+pub struct ImapStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+pub type ImapSession = Session<ImapStream>;
+
+impl ImapStream {
+    fn new(transport: Transport) -> Self {
+        ImapStream {
+            shared: Arc::new(Mutex::new(Shared {
+                transport,
+                pending: Vec::new(),
+                raw_tag: 0,
+            })),
+        }
+    }
+
+    fn raw_channel(&self) -> RawChannel {
+        RawChannel {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Read for ImapStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.pending.is_empty() {
+            let n = buf.len().min(shared.pending.len());
+            buf[..n].copy_from_slice(&shared.pending[..n]);
+            shared.pending.drain(..n);
+            return Ok(n);
+        }
+        shared.transport.read(buf)
+    }
+}
+
+impl Write for ImapStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.lock().unwrap().transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.lock().unwrap().transport.flush()
+    }
+}
+
+impl SetReadTimeout for ImapStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::error::Result<()> {
+        let shared = self.shared.lock().unwrap();
+        let tcp = match &shared.transport {
+            Transport::Tls(stream) => stream.get_ref(),
+            Transport::Plain(stream) => stream,
+        };
+        // IDLE clears the timeout after waiting, which would leave later reads unbounded
+        tcp.set_read_timeout(timeout.or(Some(IO_TIMEOUT)))
+            .map_err(imap::error::Error::Io)
+    }
+}
+
+/// Runs commands on a session's connection whose responses the `imap` crate can't parse
+/// (e.g. Gmail's X-GM-* FETCH items), using the newer `imap-proto` parser instead. Only use
+/// it between session commands, never while the session is idling.
+#[derive(Debug, Clone)]
+pub struct RawChannel {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[cfg(test)]
+impl RawChannel {
+    /// A channel over a plaintext connection, e.g. to a canned server.
+    pub fn for_test(tcp: TcpStream) -> Self {
+        ImapStream::new(Transport::Plain(tcp)).raw_channel()
+    }
+}
+
+impl RawChannel {
+    /// Sends `command` and passes every untagged response to `on_response` until the command
+    /// completes. Lines the parser doesn't understand are skipped.
+    pub fn run_command(
+        &self,
+        command: &str,
+        mut on_response: impl FnMut(Response<'_>),
+    ) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.raw_tag += 1;
+        let tag = format!("r{}", shared.raw_tag);
+        shared
+            .transport
+            .write_all(format!("{} {}\r\n", tag, command).as_bytes())?;
+        shared.transport.flush()?;
+
+        let mut buf = std::mem::take(&mut shared.pending);
+        let mut start = 0;
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let needs_data = match Response::from_bytes(&buf[start..]) {
+                Ok((rest, response)) => {
+                    let next = buf.len() - rest.len();
+                    if let Response::Done {
+                        tag: RequestId(done_tag),
+                        status,
+                        information,
+                        ..
+                    } = &response
+                    {
+                        if *done_tag == tag {
+                            let result = match status {
+                                Status::Ok => Ok(()),
+                                status => Err(anyhow::anyhow!(
+                                    "{} failed: {:?} {}",
+                                    command,
+                                    status,
+                                    information.as_deref().unwrap_or("")
+                                )),
+                            };
+                            shared.pending = rest.to_vec();
+                            return result;
+                        }
+                    }
+                    on_response(response);
+                    start = next;
+                    false
+                }
+                Err(e) if e.is_incomplete() => true,
+                Err(_) => match response_len(&buf[start..]) {
+                    Some(len) => {
+                        start += len;
+                        false
+                    }
+                    None => true,
+                },
+            };
+
+            if needs_data {
+                buf.drain(..start);
+                start = 0;
+                let n = shared.transport.read(&mut chunk)?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Connection closed during {}", command),
+                    )
+                    .into());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+/// Length of the response at the start of `buf`, including the data of any literals
+/// (`{n}\r\n` followed by n bytes), or `None` if it hasn't been read completely yet.
+fn response_len(buf: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    loop {
+        let line_end = pos + buf[pos..].windows(2).position(|w| w == b"\r\n")?;
+        let line = &buf[pos..line_end];
+        pos = line_end + 2;
+
+        let literal = line
+            .strip_suffix(b"}")
+            .and_then(|l| l.iter().rposition(|b| *b == b'{').map(|i| &l[i + 1..]))
+            .map(|n| n.strip_suffix(b"+").unwrap_or(n))
+            .and_then(|n| std::str::from_utf8(n).ok()?.parse::<usize>().ok());
+        match literal {
+            Some(n) if buf.len() >= pos + n => pos += n,
+            Some(_) => return None,
+            None => return Some(pos),
+        }
+    }
+}
+
+/// The `max_connections` cap of the account's server. It is shared by every account on the
+/// server and by everything that connects to it: fetches, IDLE watchers and reindexing.
+fn server_limit(config: &AccountConfig) -> Option<Arc<Semaphore>> {
+    static LIMITS: OnceLock<Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+    let max_connections = config.max_connections?;
+    let limits = LIMITS.get_or_init(|| Mutex::new(HashMap::new()));
+    let limit = limits
+        .lock()
+        .unwrap()
+        .entry(format!("{}:{}", config.server, config.port))
+        .or_insert_with(|| Arc::new(Semaphore::new(max_connections.max(1))))
+        .clone();
+    Some(limit)
+}
+
+/// Waits for a free connection to the account's server. The permit is held for as long as
+/// the connections it was taken for are open; `None` if the server has no cap.
+pub async fn acquire_connection(config: &AccountConfig) -> Option<OwnedSemaphorePermit> {
+    server_limit(config)?.acquire_owned().await.ok()
+}
+
+/// `acquire_connection` for blocking code.
+pub fn acquire_connection_blocking(config: &AccountConfig) -> Option<OwnedSemaphorePermit> {
+    futures::executor::block_on(acquire_connection(config))
+}
+
+/// Opens a connection to the account's server using its configured `security` mode and
+/// reads the server greeting. The returned client is ready to log in.
+pub fn connect(config: &AccountConfig) -> Result<(Client<ImapStream>, RawChannel)> {
+    let mut tcp = open_tcp(&config.server, config.port)?;
+
+    let transport = match config.security {
+        Security::Tls => Transport::Tls(Box::new(tls_handshake(config, tcp)?)),
+        Security::StartTls => {
+            starttls(&mut tcp)?;
+            let stream = ImapStream::new(Transport::Tls(Box::new(tls_handshake(config, tcp)?)));
+            let raw = stream.raw_channel();
+            // The greeting was consumed before the upgrade, none is sent afterwards
+            return Ok((Client::new(stream), raw));
+        }
+        Security::None => Transport::Plain(tcp),
+    };
+
+    let stream = ImapStream::new(transport);
+    let raw = stream.raw_channel();
+    let mut client = Client::new(stream);
+    client.read_greeting()?;
+    Ok((client, raw))
+}
+
+/// Connects to the first of the host's addresses that answers within `CONNECT_TIMEOUT`, with
+/// `IO_TIMEOUT` set for reads and writes.
+fn open_tcp(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(tcp) => {
+                tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+                tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+                return Ok(tcp);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address", host),
+        )
+    }))
+}
+
+fn tls_handshake(config: &AccountConfig, tcp: TcpStream) -> Result<TlsStream<TcpStream>> {
+    if config.tls.danger_accept_invalid_certs {
+        warn!(
+            server = %config.server,
+            "Certificate verification is disabled (danger_accept_invalid_certs)"
+        );
+    }
+
+    let connector = tls_connector(&config.tls)?;
+    let stream = connector
+        .connect(&config.server, tcp)
+        .map_err(|e| anyhow::anyhow!("TLS handshake with {} failed: {}", config.server, e))?;
+
+    if let Some(pin) = &config.tls.pin_sha256 {
+        verify_pin(&stream, pin)
+            .with_context(|| format!("Certificate pin check failed for {}", config.server))?;
+    }
+
+    Ok(stream)
+}
+
+fn tls_connector(options: &TlsOptions) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(ca_file) = &options.ca_file {
+        let pem = fs::read(ca_file)
+            .with_context(|| format!("Failed to read CA file {}", ca_file.display()))?;
+        builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+
+    if let (Some(cert_file), Some(key_file)) = (&options.client_cert, &options.client_key) {
+        let cert = fs::read(cert_file).with_context(|| {
+            format!("Failed to read client certificate {}", cert_file.display())
+        })?;
+        let key = fs::read(key_file)
+            .with_context(|| format!("Failed to read client key {}", key_file.display()))?;
+        builder.identity(Identity::from_pkcs8(&cert, &key)?);
+    }
+
+    // A pinned certificate replaces chain and hostname validation, so self-signed
+    // certificates can be pinned. The fingerprint is checked after the handshake.
+    if options.danger_accept_invalid_certs || options.pin_sha256.is_some() {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn verify_pin(stream: &TlsStream<TcpStream>, pin: &str) -> Result<()> {
+    let cert = stream
+        .peer_certificate()?
+        .ok_or_else(|| anyhow::anyhow!("Server did not present a certificate"))?;
+    check_pin(&cert.to_der()?, pin)
+}
+
+/// Compares the SHA-256 fingerprint of a DER certificate with a `tls_pin_sha256` value, which
+/// may be colon-separated and in either case.
+fn check_pin(cert_der: &[u8], pin: &str) -> Result<()> {
+    let expected = pin
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!(
+            "tls_pin_sha256 {:?} is not a SHA-256 fingerprint (64 hex digits)",
+            pin
+        ));
+    }
+
+    let actual = hex::encode(Sha256::digest(cert_der));
+    if actual != expected {
+        return Err(anyhow::anyhow!(
+            "expected SHA-256 {}, server presented {}",
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the greeting and negotiates STARTTLS on a fresh plaintext connection. This happens
+/// before the `imap` client takes over, since its `secure` upgrade can't yield an `ImapStream`.
+fn starttls(tcp: &mut TcpStream) -> Result<()> {
+    read_line(tcp)?;
+    tcp.write_all(b"s0 STARTTLS\r\n")?;
+    tcp.flush()?;
+
+    loop {
+        let line = read_line(tcp)?;
+        if let Some(status) = line.strip_prefix("s0 ") {
+            if status.starts_with("OK") {
+                return Ok(());
+            }
+            return Err(anyhow::anyhow!("STARTTLS rejected: {}", status.trim_end()));
+        }
+    }
+}
+
+/// Reads a single CRLF-terminated line byte by byte, so nothing after it is consumed before
+/// the TLS handshake starts.
+fn read_line(tcp: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\n") {
+        if tcp.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed during STARTTLS",
+            )
+            .into());
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_complete_responses() {
+        let line = b"* 1 FETCH (UID 7 X-UNKNOWN 1)\r\n";
+        let mut buf = line.to_vec();
+        buf.extend_from_slice(b"* 2 FETCH");
+        assert_eq!(response_len(&buf), Some(line.len()));
+
+        // The literal's data is part of the response, whatever it contains
+        let literal = b"* 1 FETCH (UID 7 X-UNKNOWN {8}\r\na\r\n{2}\r\n)\r\n";
+        assert_eq!(response_len(literal), Some(literal.len()));
+        let non_sync = b"* 1 FETCH (X-UNKNOWN {3+}\r\nabc)\r\n";
+        assert_eq!(response_len(non_sync), Some(non_sync.len()));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_response() {
+        assert_eq!(response_len(b""), None);
+        assert_eq!(response_len(b"* 1 FETCH (UID 7"), None);
+        // Literal announced, its data not all there yet
+        assert_eq!(response_len(b"* 1 FETCH (X-UNKNOWN {5}\r\nab"), None);
+        assert_eq!(response_len(b"* 1 FETCH (X-UNKNOWN {5}\r\nabcde"), None);
+
+        // A CRLF split across two reads
+        let mut buf = b"* 1 FETCH (UID 7)\r".to_vec();
+        assert_eq!(response_len(&buf), None);
+        buf.push(b'\n');
+        assert_eq!(response_len(&buf), Some(buf.len()));
+    }
+
+    #[test]
+    fn checks_pinned_fingerprints() {
+        let cert = b"not really a certificate";
+        let fingerprint = hex::encode(Sha256::digest(cert));
+        assert!(check_pin(cert, &fingerprint).is_ok());
+
+        // The colon-separated upper-case form shown by openssl
+        let openssl = fingerprint
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(check_pin(cert, &openssl).is_ok());
+
+        let other = hex::encode(Sha256::digest(b"another certificate"));
+        let error = check_pin(cert, &other).unwrap_err();
+        assert!(error.to_string().contains("server presented"));
+
+        for malformed in ["", "abcd", &format!("{}zz", &fingerprint[2..])] {
+            let error = check_pin(cert, malformed).unwrap_err();
+            assert!(error.to_string().contains("is not a SHA-256 fingerprint"));
+        }
+    }
+}
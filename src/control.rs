@@ -0,0 +1,83 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControlState {
+    #[default]
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// Lets the API pause, resume or cancel a running fetch. The fetcher checks in between
+/// messages, so the message being written when the request arrives is always completed.
+#[derive(Debug, Clone, Default)]
+pub struct FetchControl {
+    inner: Arc<(Mutex<ControlState>, Condvar)>,
+}
+
+impl FetchControl {
+    pub fn state(&self) -> ControlState {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Returns false if the fetch was not running.
+    pub fn pause(&self) -> bool {
+        self.transition(ControlState::Running, ControlState::Paused)
+    }
+
+    /// Returns false if the fetch was not paused.
+    pub fn resume(&self) -> bool {
+        self.transition(ControlState::Paused, ControlState::Running)
+    }
+
+    /// Cancels the fetch, waking it up if it is paused. Returns false if it already was.
+    pub fn cancel(&self) -> bool {
+        let (state, changed) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        if *state == ControlState::Cancelled {
+            return false;
+        }
+        *state = ControlState::Cancelled;
+        changed.notify_all();
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state() == ControlState::Cancelled
+    }
+
+    /// Blocks while the fetch is paused. Returns false once it has been cancelled.
+    pub fn proceed(&self) -> bool {
+        let (state, changed) = &*self.inner;
+        let state = changed
+            .wait_while(state.lock().unwrap(), |state| {
+                *state == ControlState::Paused
+            })
+            .unwrap();
+        *state == ControlState::Running
+    }
+
+    /// `proceed` for async code, waiting on a blocking thread.
+    pub async fn proceed_async(&self) -> bool {
+        match self.state() {
+            ControlState::Running => return true,
+            ControlState::Cancelled => return false,
+            ControlState::Paused => {}
+        }
+        let control = self.clone();
+        tokio::task::spawn_blocking(move || control.proceed())
+            .await
+            .unwrap_or(false)
+    }
+
+    fn transition(&self, from: ControlState, to: ControlState) -> bool {
+        let (state, changed) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        if *state != from {
+            return false;
+        }
+        *state = to;
+        changed.notify_all();
+        true
+    }
+}
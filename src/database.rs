@@ -1,9 +1,18 @@
 use anyhow::Result;
-use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
-use std::path::Path;
+use chrono::{DateTime, FixedOffset, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+/// Upper bounds of the run duration histogram buckets, in seconds (1s to 4h).
+pub const RUN_DURATION_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0,
+];
+
+/// Clones share the same connection.
+#[derive(Clone)]
 pub struct Database {
     pub conn: Arc<Mutex<Connection>>,
 }
@@ -15,6 +24,89 @@ pub struct EmailStats {
     pub count: i64,
     pub total_size_bytes: i64,
     pub last_fetch: Option<DateTime<Utc>>,
+    pub deleted_on_server: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MailboxState {
+    pub uid_validity: u32,
+    pub uid_next: Option<u32>,
+    pub highest_modseq: Option<u64>,
+}
+
+/// The IMAP state of a message that isn't part of its body.
+#[derive(Debug, Clone, Default)]
+pub struct MessageAttributes {
+    /// Flags and keywords separated by spaces, e.g. `$Forwarded \Answered \Seen`
+    pub flags: String,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+}
+
+impl MessageAttributes {
+    pub fn new<F: Display>(
+        flags: impl IntoIterator<Item = F>,
+        internal_date: Option<DateTime<FixedOffset>>,
+    ) -> Self {
+        MessageAttributes {
+            flags: format_flags(flags),
+            internal_date,
+        }
+    }
+}
+
+/// Sorts and joins flags so they compare equal regardless of server order. `\Recent` only
+/// describes the current session and is left out.
+pub fn format_flags<F: Display>(flags: impl IntoIterator<Item = F>) -> String {
+    let flags: BTreeSet<String> = flags
+        .into_iter()
+        .map(|flag| flag.to_string())
+        .filter(|flag| !flag.eq_ignore_ascii_case("\\Recent"))
+        .collect();
+    flags.into_iter().collect::<Vec<_>>().join(" ")
+}
+
+/// A message of a mailbox to record in `fetched_emails`, with its X-GM-MSGID and labels if it
+/// came from Gmail.
+#[derive(Debug, Clone)]
+pub struct FetchedMessage<'a> {
+    pub uid: u32,
+    pub file_path: &'a Path,
+    pub size_bytes: usize,
+    pub sha256: Option<&'a str>,
+    pub attributes: &'a MessageAttributes,
+    pub gmail: Option<(u64, &'a [String])>,
+}
+
+/// A `fetched_emails` row as `courrier verify` checks it against its file.
+#[derive(Debug, Clone)]
+pub struct ArchivedMessage {
+    pub account_email: String,
+    pub mailbox: String,
+    pub uid_validity: u32,
+    pub uid: u32,
+    pub file_path: String,
+    pub size_bytes: i64,
+    pub sha256: Option<String>,
+    pub deleted_on_server: bool,
+}
+
+/// A message file already in the archive, shared by several mailbox rows.
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub file_path: PathBuf,
+    pub size_bytes: usize,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UidValidityEvent {
+    pub account_email: String,
+    pub mailbox: String,
+    pub old_uid_validity: u32,
+    pub new_uid_validity: u32,
+    pub archived_messages: i64,
+    pub archive_path: String,
+    pub detected_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone)]
@@ -22,7 +114,135 @@ pub struct FetchStatus {
     #[expect(unused)]
     pub is_running: bool,
     pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub messages_fetched: i64,
+}
+
+/// Outcome of a fetch run, or of one mailbox within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchRunStatus {
+    Running,
+    Completed,
+    /// Finished, but some messages or mailboxes failed
+    CompletedWithErrors,
+    Failed,
+    /// Stopped through the API before it was done
+    Cancelled,
+    /// The process exited before the run was done, e.g. in a crash
+    Interrupted,
+}
+
+impl FetchRunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FetchRunStatus::Running => "running",
+            FetchRunStatus::Completed => "completed",
+            FetchRunStatus::CompletedWithErrors => "completed_with_errors",
+            FetchRunStatus::Failed => "failed",
+            FetchRunStatus::Cancelled => "cancelled",
+            FetchRunStatus::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// What started a fetch run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchTrigger {
+    /// `courrier fetch`
+    Cli,
+    /// `POST /api/fetch`
+    Dashboard,
+    /// `fetch_on_startup`
+    Startup,
+    /// `fetch_interval_seconds`
+    Schedule,
+    /// The refetch after a verification repaired the archive
+    Repair,
+    /// A change reported by an IDLE watcher
+    Idle,
+}
+
+impl FetchTrigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FetchTrigger::Cli => "cli",
+            FetchTrigger::Dashboard => "dashboard",
+            FetchTrigger::Startup => "startup",
+            FetchTrigger::Schedule => "schedule",
+            FetchTrigger::Repair => "repair",
+            FetchTrigger::Idle => "idle",
+        }
+    }
+}
+
+/// Counters of a mailbox sync.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FetchCounts {
+    pub messages_fetched: usize,
+    pub messages_failed: usize,
+    pub bytes_fetched: u64,
+    /// Messages left undownloaded because the fetch was cancelled
+    pub messages_cancelled: usize,
+}
+
+/// A row of `fetch_history`. `account_email`/`mailbox` are the account and mailbox pattern
+/// the run was limited to, if any. `triggered_by` is a `FetchTrigger` name, unset on runs
+/// from before it was recorded.
+#[derive(Debug, Clone)]
+pub struct FetchRun {
+    pub id: i64,
+    pub account_email: Option<String>,
+    pub mailbox: Option<String>,
+    pub triggered_by: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub status: String,
+    pub messages_fetched: i64,
+    pub messages_failed: i64,
+    pub bytes_fetched: i64,
+    pub mailboxes: Vec<MailboxFetch>,
+}
+
+/// A row of `fetch_history_mailboxes`. `mailbox` is unset when the account itself failed,
+/// e.g. on a login error.
+#[derive(Debug, Clone)]
+pub struct MailboxFetch {
+    pub account_email: String,
+    pub mailbox: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub status: String,
+    pub messages_fetched: i64,
+    pub messages_failed: i64,
+    pub bytes_fetched: i64,
+    pub error: Option<String>,
+}
+
+/// `fetch_history_mailboxes` summed over all runs of one mailbox.
+#[derive(Debug, Clone)]
+pub struct FetchTotals {
+    pub account_email: String,
+    pub mailbox: String,
     pub messages_fetched: i64,
+    pub messages_failed: i64,
+    pub bytes_fetched: i64,
+}
+
+/// A row of `fetch_failures`: a message that could not be downloaded, kept until a later
+/// run saves it. Skipped messages are no longer fetched.
+#[derive(Debug, Clone)]
+pub struct FetchFailure {
+    pub id: i64,
+    pub account_email: String,
+    pub mailbox: String,
+    pub uid_validity: u32,
+    pub uid: u32,
+    pub error_class: String,
+    pub error: String,
+    pub attempts: i64,
+    pub first_failed_at: Option<DateTime<Utc>>,
+    pub last_failed_at: Option<DateTime<Utc>>,
+    pub skipped_at: Option<DateTime<Utc>>,
 }
 
 impl Database {
@@ -43,31 +263,212 @@ impl Database {
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 account_email TEXT NOT NULL,
                 mailbox TEXT NOT NULL,
+                uid_validity INTEGER NOT NULL DEFAULT 0,
                 uid INTEGER NOT NULL,
                 file_path TEXT NOT NULL,
                 size_bytes INTEGER NOT NULL,
                 fetched_at TEXT NOT NULL,
-                UNIQUE(account_email, mailbox, uid)
+                UNIQUE(account_email, mailbox, uid_validity, uid)
             )",
             [],
         )?;
 
+        // Databases created before UIDVALIDITY tracking keyed rows by (account, mailbox, uid)
+        // only. Rebuild the table so rows of different UIDVALIDITY generations can coexist.
+        if !has_column(&conn, "fetched_emails", "uid_validity")? {
+            // A failure rolls the rebuild back when the transaction is dropped
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(
+                "CREATE TABLE fetched_emails_migrated (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    account_email TEXT NOT NULL,
+                    mailbox TEXT NOT NULL,
+                    uid_validity INTEGER NOT NULL DEFAULT 0,
+                    uid INTEGER NOT NULL,
+                    file_path TEXT NOT NULL,
+                    size_bytes INTEGER NOT NULL,
+                    fetched_at TEXT NOT NULL,
+                    UNIQUE(account_email, mailbox, uid_validity, uid)
+                 );
+                 INSERT INTO fetched_emails_migrated
+                    (id, account_email, mailbox, uid, file_path, size_bytes, fetched_at)
+                    SELECT id, account_email, mailbox, uid, file_path, size_bytes, fetched_at
+                    FROM fetched_emails;
+                 DROP TABLE fetched_emails;
+                 ALTER TABLE fetched_emails_migrated RENAME TO fetched_emails;",
+            )?;
+            tx.commit()?;
+        }
+
+        // Gmail messages are stored once per X-GM-MSGID, every label-mailbox row points at it
+        add_column_if_missing(&conn, "fetched_emails", "gmail_msgid", "INTEGER")?;
+        // SHA-256 of the raw message, also the blob name when the blob store is used
+        add_column_if_missing(&conn, "fetched_emails", "sha256", "TEXT")?;
+        // Flags/keywords and INTERNALDATE, so a restore can rebuild the mailbox as it was
+        add_column_if_missing(&conn, "fetched_emails", "flags", "TEXT")?;
+        add_column_if_missing(&conn, "fetched_emails", "internal_date", "TEXT")?;
+        // Set once the message is no longer on the server, cleared if it shows up again
+        add_column_if_missing(&conn, "fetched_emails", "deleted_on_server_at", "TEXT")?;
+
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS fetch_history (
+            "CREATE TABLE IF NOT EXISTS gmail_messages (
+                account_email TEXT NOT NULL,
+                gmail_msgid INTEGER NOT NULL,
+                file_path TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                fetched_at TEXT NOT NULL,
+                sha256 TEXT,
+                PRIMARY KEY(account_email, gmail_msgid)
+            )",
+            [],
+        )?;
+        add_column_if_missing(&conn, "gmail_messages", "sha256", "TEXT")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS gmail_labels (
+                account_email TEXT NOT NULL,
+                gmail_msgid INTEGER NOT NULL,
+                label TEXT NOT NULL,
+                PRIMARY KEY(account_email, gmail_msgid, label)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS mailbox_state (
+                account_email TEXT NOT NULL,
+                mailbox TEXT NOT NULL,
+                uid_validity INTEGER NOT NULL,
+                uid_next INTEGER,
+                highest_modseq INTEGER,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY(account_email, mailbox)
+            )",
+            [],
+        )?;
+        add_column_if_missing(&conn, "mailbox_state", "uid_next", "INTEGER")?;
+        add_column_if_missing(&conn, "mailbox_state", "highest_modseq", "INTEGER")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS uid_validity_events (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 account_email TEXT NOT NULL,
                 mailbox TEXT NOT NULL,
+                old_uid_validity INTEGER NOT NULL,
+                new_uid_validity INTEGER NOT NULL,
+                archived_messages INTEGER NOT NULL,
+                archive_path TEXT NOT NULL,
+                detected_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // One row per fetch run. The first version of this table required an account and
+        // mailbox per row and was never written to, so it is simply recreated.
+        if !has_column(&conn, "fetch_history", "messages_failed")? {
+            conn.execute("DROP TABLE IF EXISTS fetch_history", [])?;
+        }
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS fetch_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                account_email TEXT,
+                mailbox TEXT,
                 started_at TEXT NOT NULL,
                 completed_at TEXT,
                 messages_fetched INTEGER NOT NULL DEFAULT 0,
+                messages_failed INTEGER NOT NULL DEFAULT 0,
+                bytes_fetched INTEGER NOT NULL DEFAULT 0,
                 status TEXT NOT NULL DEFAULT 'running'
             )",
             [],
         )?;
 
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS fetch_history_mailboxes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                run_id INTEGER NOT NULL REFERENCES fetch_history(id),
+                account_email TEXT NOT NULL,
+                mailbox TEXT,
+                started_at TEXT NOT NULL,
+                completed_at TEXT,
+                messages_fetched INTEGER NOT NULL DEFAULT 0,
+                messages_failed INTEGER NOT NULL DEFAULT 0,
+                bytes_fetched INTEGER NOT NULL DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'running',
+                error TEXT
+            )",
+            [],
+        )?;
+        // An `ErrorClass` name, unset on rows from before it was recorded
+        add_column_if_missing(&conn, "fetch_history_mailboxes", "error_class", "TEXT")?;
+        // A `FetchTrigger` name. Before it was recorded, only runs started by IDLE had an
+        // account set.
+        if !has_column(&conn, "fetch_history", "triggered_by")? {
+            conn.execute("ALTER TABLE fetch_history ADD COLUMN triggered_by TEXT", [])?;
+            conn.execute(
+                "UPDATE fetch_history SET triggered_by = 'idle' WHERE account_email IS NOT NULL",
+                [],
+            )?;
+        }
+
+        // Finished runs counted per duration bucket, so the metrics don't have to go through
+        // the whole history. `upper_bound` is a value of `RUN_DURATION_BUCKETS`, or infinity
+        // for longer runs.
+        let durations_counted = has_column(&conn, "fetch_run_durations", "upper_bound")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS fetch_run_durations (
+                upper_bound REAL PRIMARY KEY,
+                runs INTEGER NOT NULL,
+                total_seconds REAL NOT NULL
+            )",
+            [],
+        )?;
+        if !durations_counted {
+            let mut stmt = conn.prepare(
+                "SELECT started_at, completed_at FROM fetch_history
+                 WHERE completed_at IS NOT NULL",
+            )?;
+            let runs: Vec<(Option<String>, Option<String>)> = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            for (started_at, completed_at) in runs {
+                if let Some((started_at, completed_at)) =
+                    parse_timestamp(started_at).zip(parse_timestamp(completed_at))
+                {
+                    count_run_duration(&conn, completed_at - started_at)?;
+                }
+            }
+        }
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_fetch_history_mailboxes_run
+             ON fetch_history_mailboxes(run_id)",
+            [],
+        )?;
+
+        // Messages that failed to download, one row per message until it is saved. `attempts`
+        // counts the runs it failed in.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS fetch_failures (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                account_email TEXT NOT NULL,
+                mailbox TEXT NOT NULL,
+                uid_validity INTEGER NOT NULL,
+                uid INTEGER NOT NULL,
+                error_class TEXT NOT NULL,
+                error TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 1,
+                first_failed_at TEXT NOT NULL,
+                last_failed_at TEXT NOT NULL,
+                skipped_at TEXT,
+                UNIQUE(account_email, mailbox, uid_validity, uid)
+            )",
+            [],
+        )?;
+
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_fetched_emails_lookup 
-             ON fetched_emails(account_email, mailbox, uid)",
+             ON fetched_emails(account_email, mailbox, uid_validity, uid)",
             [],
         )?;
 
@@ -80,44 +481,517 @@ impl Database {
         Ok(())
     }
 
-    pub fn mark_email_fetched(
+    /// Records downloaded messages of one mailbox in a single transaction, so either all of
+    /// them are recorded or none is.
+    pub fn record_fetched_messages(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        messages: &[FetchedMessage],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for message in messages {
+            insert_fetched_email(&tx, account_email, mailbox, uid_validity, message)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records a Gmail message seen in `mailbox`. The message file is shared by every mailbox
+    /// (label) the message appears in, and its current labels replace the stored ones.
+    pub fn record_gmail_message(
         &self,
         account_email: &str,
         mailbox: &str,
-        uid: u32,
-        file_path: &Path,
+        uid_validity: u32,
+        message: &FetchedMessage,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        insert_fetched_email(&tx, account_email, mailbox, uid_validity, message)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Looks up which of `msgids` are already stored for the account.
+    pub fn get_gmail_messages(
+        &self,
+        account_email: &str,
+        msgids: &[u64],
+    ) -> Result<HashMap<u64, StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT file_path, size_bytes, sha256 FROM gmail_messages
+             WHERE account_email = ?1 AND gmail_msgid = ?2",
+        )?;
+
+        let mut stored = HashMap::new();
+        for msgid in msgids {
+            let row = stmt
+                .query_row(params![account_email, *msgid as i64], |row| {
+                    Ok(StoredMessage {
+                        file_path: PathBuf::from(row.get::<_, String>(0)?),
+                        size_bytes: row.get::<_, i64>(1)? as usize,
+                        sha256: row.get(2)?,
+                    })
+                })
+                .optional()?;
+            if let Some(row) = row {
+                stored.insert(*msgid, row);
+            }
+        }
+        Ok(stored)
+    }
+
+    /// All distinct message files referenced by the archive.
+    pub fn get_archived_file_paths(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT DISTINCT file_path FROM fetched_emails")?;
+        let paths: Result<Vec<String>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+        Ok(paths?)
+    }
+
+    /// Every archived message row, ordered by file so rows sharing a file are adjacent.
+    pub fn get_archived_messages(&self) -> Result<Vec<ArchivedMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT account_email, mailbox, uid_validity, uid, file_path, size_bytes, sha256,
+                    deleted_on_server_at IS NOT NULL
+             FROM fetched_emails
+             ORDER BY file_path, id",
+        )?;
+        let messages = stmt
+            .query_map([], |row| {
+                Ok(ArchivedMessage {
+                    account_email: row.get(0)?,
+                    mailbox: row.get(1)?,
+                    uid_validity: row.get::<_, i64>(2)? as u32,
+                    uid: row.get::<_, i64>(3)? as u32,
+                    file_path: row.get(4)?,
+                    size_bytes: row.get(5)?,
+                    sha256: row.get(6)?,
+                    deleted_on_server: row.get(7)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(messages)
+    }
+
+    /// Files of Gmail messages, which may be referenced by `gmail_messages` alone.
+    pub fn get_gmail_file_paths(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT DISTINCT file_path FROM gmail_messages")?;
+        let paths = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(paths)
+    }
+
+    /// Removes every row pointing at a file that is gone or damaged, so the messages are
+    /// downloaded again instead of being linked to it. Returns the number of removed
+    /// `fetched_emails` rows.
+    pub fn forget_archived_file(&self, file_path: &str) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM fetched_emails WHERE file_path = ?1",
+            params![file_path],
+        )?;
+        tx.execute(
+            "DELETE FROM gmail_messages WHERE file_path = ?1",
+            params![file_path],
+        )?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Points every row that referenced `old_path` at the blob its content was moved to.
+    pub fn point_files_to_blob(
+        &self,
+        old_path: &str,
+        sha256: &str,
+        blob_path: &Path,
         size_bytes: usize,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let blob_path = blob_path.to_string_lossy();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE fetched_emails SET file_path = ?2, sha256 = ?3, size_bytes = ?4
+             WHERE file_path = ?1",
+            params![old_path, blob_path, sha256, size_bytes as i64],
+        )?;
+        tx.execute(
+            "UPDATE gmail_messages SET file_path = ?2, sha256 = ?3 WHERE file_path = ?1",
+            params![old_path, blob_path, sha256],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_fetched_uids(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+    ) -> Result<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT uid FROM fetched_emails 
+             WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3",
+        )?;
+        let uids: Result<Vec<u32>, _> = stmt
+            .query_map(params![account_email, mailbox, uid_validity], |row| {
+                Ok(row.get::<_, i64>(0)? as u32)
+            })?
+            .collect();
+        Ok(uids?)
+    }
+
+    /// Stores the current flags of already archived messages. Returns how many rows changed.
+    pub fn update_message_flags(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        flags: &HashMap<u32, String>,
+    ) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut changed = 0;
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE fetched_emails SET flags = ?5
+                 WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3 AND uid = ?4
+                   AND flags IS NOT ?5",
+            )?;
+            for (uid, flags) in flags {
+                changed +=
+                    stmt.execute(params![account_email, mailbox, uid_validity, uid, flags])?;
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// UIDs of the generation that are currently marked as deleted on the server.
+    pub fn get_deleted_uids(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+    ) -> Result<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT uid FROM fetched_emails
+             WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3
+               AND deleted_on_server_at IS NOT NULL",
+        )?;
+        let uids: Result<Vec<u32>, _> = stmt
+            .query_map(params![account_email, mailbox, uid_validity], |row| {
+                Ok(row.get::<_, i64>(0)? as u32)
+            })?
+            .collect();
+        Ok(uids?)
+    }
+
+    /// Marks messages as deleted on the server, or clears the mark of messages that are back
+    /// when `deleted` is false. Returns the distinct files of the affected rows.
+    pub fn set_deleted_on_server(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        uids: &[u32],
+        deleted: bool,
+    ) -> Result<Vec<String>> {
+        let mut conn = self.conn.lock().unwrap();
+        let deleted_at = deleted.then(|| Utc::now().to_rfc3339());
+        let tx = conn.transaction()?;
+        let mut file_paths = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE fetched_emails SET deleted_on_server_at = ?5
+                 WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3 AND uid = ?4
+                 RETURNING file_path",
+            )?;
+            for uid in uids {
+                let file_path: Option<String> = stmt
+                    .query_row(
+                        params![account_email, mailbox, uid_validity, uid, deleted_at],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(file_path) = file_path {
+                    if !file_paths.contains(&file_path) {
+                        file_paths.push(file_path);
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(file_paths)
+    }
+
+    /// Number of rows still on the server (in any mailbox or account) that use `file_path`.
+    pub fn count_live_references(&self, file_path: &str) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let count = conn.query_row(
+            "SELECT COUNT(*) FROM fetched_emails
+             WHERE file_path = ?1 AND deleted_on_server_at IS NULL",
+            params![file_path],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Points every row that used `old_path` at `new_path`, after the file was moved.
+    pub fn move_message_file(&self, old_path: &str, new_path: &Path) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let new_path = new_path.to_string_lossy();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE fetched_emails SET file_path = ?2 WHERE file_path = ?1",
+            params![old_path, new_path],
+        )?;
+        tx.execute(
+            "UPDATE gmail_messages SET file_path = ?2 WHERE file_path = ?1",
+            params![old_path, new_path],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes the rows of an account that were deleted on the server before `cutoff`.
+    /// Returns the number of rows removed and the files no row refers to anymore.
+    pub fn purge_deleted_messages(
+        &self,
+        account_email: &str,
+        cutoff: DateTime<Utc>,
+    ) -> Result<(usize, Vec<String>)> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let file_paths: Vec<String> = {
+            let mut stmt = tx.prepare(
+                "SELECT DISTINCT file_path FROM fetched_emails
+                 WHERE account_email = ?1 AND deleted_on_server_at < ?2",
+            )?;
+            let paths = stmt
+                .query_map(params![account_email, cutoff.to_rfc3339()], |row| {
+                    row.get(0)
+                })?
+                .collect::<Result<_, _>>()?;
+            paths
+        };
+        let purged = tx.execute(
+            "DELETE FROM fetched_emails WHERE account_email = ?1 AND deleted_on_server_at < ?2",
+            params![account_email, cutoff.to_rfc3339()],
+        )?;
+
+        let mut unreferenced = Vec::new();
+        for file_path in file_paths {
+            let references: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM fetched_emails WHERE file_path = ?1",
+                params![file_path],
+                |row| row.get(0),
+            )?;
+            if references == 0 {
+                tx.execute(
+                    "DELETE FROM gmail_labels WHERE (account_email, gmail_msgid) IN
+                     (SELECT account_email, gmail_msgid FROM gmail_messages WHERE file_path = ?1)",
+                    params![file_path],
+                )?;
+                tx.execute(
+                    "DELETE FROM gmail_messages WHERE file_path = ?1",
+                    params![file_path],
+                )?;
+                unreferenced.push(file_path);
+            }
+        }
+        tx.commit()?;
+
+        Ok((purged, unreferenced))
+    }
+
+    pub fn get_mailbox_state(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+    ) -> Result<Option<MailboxState>> {
+        let conn = self.conn.lock().unwrap();
+        let state = conn
+            .query_row(
+                "SELECT uid_validity, uid_next, highest_modseq FROM mailbox_state
+                 WHERE account_email = ?1 AND mailbox = ?2",
+                params![account_email, mailbox],
+                |row| {
+                    Ok(MailboxState {
+                        uid_validity: row.get::<_, i64>(0)? as u32,
+                        uid_next: row.get::<_, Option<i64>>(1)?.map(|v| v as u32),
+                        highest_modseq: row.get::<_, Option<i64>>(2)?.map(|v| v as u64),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(state)
+    }
+
+    /// Stores the UIDNEXT/HIGHESTMODSEQ a mailbox has been synced up to, so the next run only
+    /// has to look at messages that arrived or changed since.
+    pub fn update_mailbox_sync_state(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_next: Option<u32>,
+        highest_modseq: Option<u64>,
     ) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         let now = Utc::now().to_rfc3339();
         conn.execute(
-            "INSERT OR REPLACE INTO fetched_emails 
-             (account_email, mailbox, uid, file_path, size_bytes, fetched_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "UPDATE mailbox_state SET uid_next = ?3, highest_modseq = ?4, updated_at = ?5
+             WHERE account_email = ?1 AND mailbox = ?2",
             params![
                 account_email,
                 mailbox,
-                uid,
-                file_path.to_string_lossy(),
-                size_bytes as i64,
+                uid_next,
+                highest_modseq.map(|v| v as i64),
                 now
             ],
         )?;
         Ok(())
     }
 
-    pub fn get_fetched_uids(&self, account_email: &str, mailbox: &str) -> Result<Vec<u32>> {
+    /// Records the UIDVALIDITY of a mailbox seen for the first time. Rows archived before
+    /// UIDVALIDITY was tracked are adopted into this generation.
+    pub fn set_mailbox_uid_validity(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO mailbox_state (account_email, mailbox, uid_validity, updated_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![account_email, mailbox, uid_validity, now],
+        )?;
+        tx.execute(
+            "UPDATE OR IGNORE fetched_emails SET uid_validity = ?3
+             WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = 0",
+            params![account_email, mailbox, uid_validity],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Starts a new UIDVALIDITY generation for a mailbox. Rows of the previous generation are
+    /// kept and pointed at `archive_dir`, where the fetcher moved their files.
+    pub fn start_uid_validity_generation(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        old_uid_validity: u32,
+        new_uid_validity: u32,
+        archive_dir: &Path,
+    ) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        let tx = conn.transaction()?;
+
+        let rows: Vec<(i64, String)> = {
+            let mut stmt = tx.prepare(
+                "SELECT id, file_path FROM fetched_emails
+                 WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3",
+            )?;
+            let rows = stmt
+                .query_map(params![account_email, mailbox, old_uid_validity], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        // Only files in the mailbox directory were moved. Gmail and blob store messages are
+        // shared with other mailboxes and stay where they are.
+        let mailbox_dir = archive_dir.parent();
+        for (id, file_path) in rows
+            .iter()
+            .filter(|(_, file_path)| Path::new(file_path).parent() == mailbox_dir)
+        {
+            if let Some(file_name) = Path::new(file_path).file_name() {
+                tx.execute(
+                    "UPDATE fetched_emails SET file_path = ?1 WHERE id = ?2",
+                    params![archive_dir.join(file_name).to_string_lossy(), id],
+                )?;
+            }
+        }
+
+        tx.execute(
+            "INSERT INTO uid_validity_events
+             (account_email, mailbox, old_uid_validity, new_uid_validity, archived_messages,
+              archive_path, detected_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                account_email,
+                mailbox,
+                old_uid_validity,
+                new_uid_validity,
+                rows.len() as i64,
+                archive_dir.to_string_lossy(),
+                now
+            ],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO mailbox_state (account_email, mailbox, uid_validity, updated_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![account_email, mailbox, new_uid_validity, now],
+        )?;
+        // The UIDs of the old generation no longer name the same messages
+        tx.execute(
+            "DELETE FROM fetch_failures
+             WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3",
+            params![account_email, mailbox, old_uid_validity],
+        )?;
+        tx.commit()?;
+
+        Ok(rows.len())
+    }
+
+    pub fn get_uid_validity_events(&self, limit: i64) -> Result<Vec<UidValidityEvent>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT uid FROM fetched_emails 
-             WHERE account_email = ?1 AND mailbox = ?2",
+            "SELECT account_email, mailbox, old_uid_validity, new_uid_validity,
+                    archived_messages, archive_path, detected_at
+             FROM uid_validity_events
+             ORDER BY detected_at DESC
+             LIMIT ?1",
         )?;
-        let uids: Result<Vec<u32>, _> = stmt
-            .query_map(params![account_email, mailbox], |row| {
-                Ok(row.get::<_, i64>(0)? as u32)
+
+        let events: Result<Vec<UidValidityEvent>, _> = stmt
+            .query_map(params![limit], |row| {
+                let detected_at_str: String = row.get(6)?;
+                let detected_at = DateTime::parse_from_rfc3339(&detected_at_str)
+                    .ok()
+                    .map(|dt| dt.with_timezone(&Utc));
+
+                Ok(UidValidityEvent {
+                    account_email: row.get(0)?,
+                    mailbox: row.get(1)?,
+                    old_uid_validity: row.get::<_, i64>(2)? as u32,
+                    new_uid_validity: row.get::<_, i64>(3)? as u32,
+                    archived_messages: row.get(4)?,
+                    archive_path: row.get(5)?,
+                    detected_at,
+                })
             })?
             .collect();
-        Ok(uids?)
+
+        Ok(events?)
     }
 
     pub fn get_stats(&self) -> Result<Vec<EmailStats>> {
@@ -128,7 +1002,8 @@ impl Database {
                 mailbox,
                 COUNT(*) as count,
                 SUM(size_bytes) as total_size_bytes,
-                MAX(fetched_at) as last_fetch
+                MAX(fetched_at) as last_fetch,
+                COUNT(deleted_on_server_at) as deleted_on_server
              FROM fetched_emails
              GROUP BY account_email, mailbox
              ORDER BY account_email, mailbox",
@@ -141,6 +1016,7 @@ impl Database {
                 let count: i64 = row.get(2)?;
                 let total_size_bytes: Option<i64> = row.get(3)?;
                 let last_fetch_str: Option<String> = row.get(4)?;
+                let deleted_on_server: i64 = row.get(5)?;
 
                 let last_fetch = last_fetch_str
                     .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
@@ -152,6 +1028,7 @@ impl Database {
                     count,
                     total_size_bytes: total_size_bytes.unwrap_or(0),
                     last_fetch,
+                    deleted_on_server,
                 })
             })?
             .collect();
@@ -159,13 +1036,19 @@ impl Database {
         Ok(stats?)
     }
 
+    /// Totals over stored files, so a message stored once (Gmail labels, blob store) but
+    /// listed in several mailboxes counts once.
     pub fn get_total_stats(&self) -> Result<(i64, i64)> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT 
                 COUNT(*) as total_count,
                 SUM(size_bytes) as total_size_bytes
-             FROM fetched_emails",
+             FROM (
+                SELECT MAX(size_bytes) as size_bytes
+                FROM fetched_emails
+                GROUP BY file_path
+             )",
         )?;
 
         let row = stmt.query_row([], |row| {
@@ -178,12 +1061,471 @@ impl Database {
         Ok(row)
     }
 
+    /// Starts a fetch run, limited to one mailbox if `account_email`/`mailbox` are given.
+    pub fn start_fetch_run(
+        &self,
+        account_email: Option<&str>,
+        mailbox: Option<&str>,
+        trigger: FetchTrigger,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        conn.execute(
+            "INSERT INTO fetch_history (account_email, mailbox, triggered_by, started_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![account_email, mailbox, trigger.as_str(), now],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Completes a run. Unless it was cancelled, its status follows from its mailboxes: failed
+    /// if all of them failed, completed with errors if any of them did not complete cleanly.
+    pub fn finish_fetch_run(&self, run_id: i64, cancelled: bool) -> Result<FetchRunStatus> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = Utc::now();
+        let tx = conn.transaction()?;
+        let (total, failed, clean): (i64, i64, i64) = tx.query_row(
+            "SELECT COUNT(*),
+                    COALESCE(SUM(status = ?2), 0),
+                    COALESCE(SUM(status = ?3), 0)
+             FROM fetch_history_mailboxes WHERE run_id = ?1",
+            params![
+                run_id,
+                FetchRunStatus::Failed.as_str(),
+                FetchRunStatus::Completed.as_str()
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let status = if cancelled {
+            FetchRunStatus::Cancelled
+        } else if total > 0 && failed == total {
+            FetchRunStatus::Failed
+        } else if clean < total {
+            FetchRunStatus::CompletedWithErrors
+        } else {
+            FetchRunStatus::Completed
+        };
+        tx.execute(
+            "UPDATE fetch_history SET completed_at = ?2, status = ?3 WHERE id = ?1",
+            params![run_id, now.to_rfc3339(), status.as_str()],
+        )?;
+        let started_at: Option<String> = tx.query_row(
+            "SELECT started_at FROM fetch_history WHERE id = ?1",
+            params![run_id],
+            |row| row.get(0),
+        )?;
+        if let Some(started_at) = parse_timestamp(started_at) {
+            count_run_duration(&tx, now - started_at)?;
+        }
+        tx.commit()?;
+        Ok(status)
+    }
+
+    /// Marks the runs and mailbox entries still running as interrupted, for when no fetch can
+    /// be running: they were left behind by a process that exited in the middle of a fetch.
+    /// Returns the number of runs.
+    pub fn interrupt_unfinished_runs(&self) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let runs = tx.execute(
+            "UPDATE fetch_history SET status = ?2 WHERE status = ?1",
+            params![
+                FetchRunStatus::Running.as_str(),
+                FetchRunStatus::Interrupted.as_str()
+            ],
+        )?;
+        tx.execute(
+            "UPDATE fetch_history_mailboxes SET status = ?2 WHERE status = ?1",
+            params![
+                FetchRunStatus::Running.as_str(),
+                FetchRunStatus::Interrupted.as_str()
+            ],
+        )?;
+        tx.commit()?;
+        Ok(runs)
+    }
+
+    /// Starts the entry of one mailbox (or, with `mailbox` unset, an account) within a run.
+    pub fn start_mailbox_fetch(
+        &self,
+        run_id: i64,
+        account_email: &str,
+        mailbox: Option<&str>,
+    ) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        conn.execute(
+            "INSERT INTO fetch_history_mailboxes (run_id, account_email, mailbox, started_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![run_id, account_email, mailbox, now],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Completes a mailbox entry and adds its counts to the run, so a running fetch reports
+    /// progress mailbox by mailbox. `error` is the class and message of the error the mailbox
+    /// failed with.
+    pub fn finish_mailbox_fetch(
+        &self,
+        entry_id: i64,
+        counts: &FetchCounts,
+        error: Option<(&str, &str)>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        let status = if error.is_some() {
+            FetchRunStatus::Failed
+        } else if counts.messages_cancelled > 0 {
+            FetchRunStatus::Cancelled
+        } else if counts.messages_failed > 0 {
+            FetchRunStatus::CompletedWithErrors
+        } else {
+            FetchRunStatus::Completed
+        };
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE fetch_history_mailboxes
+             SET completed_at = ?2, status = ?3, messages_fetched = ?4, messages_failed = ?5,
+                 bytes_fetched = ?6, error_class = ?7, error = ?8
+             WHERE id = ?1",
+            params![
+                entry_id,
+                now,
+                status.as_str(),
+                counts.messages_fetched as i64,
+                counts.messages_failed as i64,
+                counts.bytes_fetched as i64,
+                error.map(|(class, _)| class),
+                error.map(|(_, message)| message)
+            ],
+        )?;
+        tx.execute(
+            "UPDATE fetch_history
+             SET messages_fetched = messages_fetched + ?2,
+                 messages_failed = messages_failed + ?3,
+                 bytes_fetched = bytes_fetched + ?4
+             WHERE id = (SELECT run_id FROM fetch_history_mailboxes WHERE id = ?1)",
+            params![
+                entry_id,
+                counts.messages_fetched as i64,
+                counts.messages_failed as i64,
+                counts.bytes_fetched as i64
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// A page of fetch runs, newest first, with their mailbox entries. Also returns the total
+    /// number of runs.
+    pub fn get_fetch_history(&self, limit: i64, offset: i64) -> Result<(i64, Vec<FetchRun>)> {
+        let conn = self.conn.lock().unwrap();
+        let total: i64 =
+            conn.query_row("SELECT COUNT(*) FROM fetch_history", [], |row| row.get(0))?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, account_email, mailbox, triggered_by, started_at, completed_at, status,
+                    messages_fetched, messages_failed, bytes_fetched
+             FROM fetch_history
+             ORDER BY id DESC
+             LIMIT ?1 OFFSET ?2",
+        )?;
+        let mut runs: Vec<FetchRun> = stmt
+            .query_map(params![limit, offset], |row| {
+                Ok(FetchRun {
+                    id: row.get(0)?,
+                    account_email: row.get(1)?,
+                    mailbox: row.get(2)?,
+                    triggered_by: row.get(3)?,
+                    started_at: parse_timestamp(row.get(4)?),
+                    completed_at: parse_timestamp(row.get(5)?),
+                    status: row.get(6)?,
+                    messages_fetched: row.get(7)?,
+                    messages_failed: row.get(8)?,
+                    bytes_fetched: row.get(9)?,
+                    mailboxes: Vec::new(),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT account_email, mailbox, started_at, completed_at, status, messages_fetched,
+                    messages_failed, bytes_fetched, error
+             FROM fetch_history_mailboxes
+             WHERE run_id = ?1
+             ORDER BY id",
+        )?;
+        for run in &mut runs {
+            run.mailboxes = stmt
+                .query_map(params![run.id], |row| {
+                    Ok(MailboxFetch {
+                        account_email: row.get(0)?,
+                        mailbox: row.get(1)?,
+                        started_at: parse_timestamp(row.get(2)?),
+                        completed_at: parse_timestamp(row.get(3)?),
+                        status: row.get(4)?,
+                        messages_fetched: row.get(5)?,
+                        messages_failed: row.get(6)?,
+                        bytes_fetched: row.get(7)?,
+                        error: row.get(8)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+        }
+
+        Ok((total, runs))
+    }
+
+    /// Counts of every recorded sync, summed per account and mailbox.
+    pub fn get_fetch_totals(&self) -> Result<Vec<FetchTotals>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT account_email, mailbox, SUM(messages_fetched), SUM(messages_failed),
+                    SUM(bytes_fetched)
+             FROM fetch_history_mailboxes
+             WHERE mailbox IS NOT NULL
+             GROUP BY account_email, mailbox
+             ORDER BY account_email, mailbox",
+        )?;
+        let totals = stmt
+            .query_map([], |row| {
+                Ok(FetchTotals {
+                    account_email: row.get(0)?,
+                    mailbox: row.get(1)?,
+                    messages_fetched: row.get(2)?,
+                    messages_failed: row.get(3)?,
+                    bytes_fetched: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(totals)
+    }
+
+    /// How often accounts and mailboxes failed, per account and error class. Failures from
+    /// before the class was recorded count as `other`.
+    pub fn get_fetch_errors(&self) -> Result<Vec<(String, String, i64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT account_email, COALESCE(error_class, 'other'), COUNT(*)
+             FROM fetch_history_mailboxes
+             WHERE error IS NOT NULL
+             GROUP BY account_email, COALESCE(error_class, 'other')",
+        )?;
+        let errors = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(errors)
+    }
+
+    /// Finished runs per duration bucket: the bucket's upper bound in seconds, the number of
+    /// runs and their total duration in seconds.
+    pub fn get_fetch_run_durations(&self) -> Result<Vec<(f64, i64, f64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT upper_bound, runs, total_seconds FROM fetch_run_durations
+             ORDER BY upper_bound",
+        )?;
+        let buckets = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(buckets)
+    }
+
+    /// When each account last had a mailbox synced, including syncs where single messages
+    /// failed.
+    pub fn get_last_successful_syncs(&self) -> Result<HashMap<String, DateTime<Utc>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT account_email, MAX(completed_at)
+             FROM fetch_history_mailboxes
+             WHERE mailbox IS NOT NULL AND status IN (?1, ?2)
+             GROUP BY account_email",
+        )?;
+        let syncs = stmt
+            .query_map(
+                params![
+                    FetchRunStatus::Completed.as_str(),
+                    FetchRunStatus::CompletedWithErrors.as_str()
+                ],
+                |row| Ok((row.get::<_, String>(0)?, parse_timestamp(row.get(1)?))),
+            )?
+            .filter_map(|row| match row {
+                Ok((account, Some(completed_at))) => Some(Ok((account, completed_at))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(syncs)
+    }
+
+    /// Records messages that failed to download in a mailbox sync. A message that failed
+    /// before keeps its first failure time and counts another attempt.
+    pub fn record_fetch_failures(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        failures: &[(u32, &str, &str)],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO fetch_failures
+                 (account_email, mailbox, uid_validity, uid, error_class, error,
+                  first_failed_at, last_failed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)
+                 ON CONFLICT(account_email, mailbox, uid_validity, uid) DO UPDATE SET
+                    error_class = excluded.error_class,
+                    error = excluded.error,
+                    attempts = attempts + 1,
+                    last_failed_at = excluded.last_failed_at",
+            )?;
+            for (uid, error_class, error) in failures {
+                stmt.execute(params![
+                    account_email,
+                    mailbox,
+                    uid_validity,
+                    uid,
+                    error_class,
+                    error,
+                    now
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Forgets the failures of messages that have been saved since.
+    pub fn resolve_fetch_failures(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        uids: &[u32],
+    ) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut resolved = 0;
+        {
+            let mut stmt = tx.prepare(
+                "DELETE FROM fetch_failures
+                 WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3 AND uid = ?4",
+            )?;
+            for uid in uids {
+                resolved += stmt.execute(params![account_email, mailbox, uid_validity, uid])?;
+            }
+        }
+        tx.commit()?;
+        Ok(resolved)
+    }
+
+    /// UIDs of the failed messages of a mailbox, either those marked as skipped or those
+    /// still waiting for another attempt.
+    pub fn get_failed_uids(
+        &self,
+        account_email: &str,
+        mailbox: &str,
+        uid_validity: u32,
+        skipped: bool,
+    ) -> Result<Vec<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT uid FROM fetch_failures
+             WHERE account_email = ?1 AND mailbox = ?2 AND uid_validity = ?3
+               AND (skipped_at IS NOT NULL) = ?4
+             ORDER BY uid",
+        )?;
+        let uids = stmt
+            .query_map(
+                params![account_email, mailbox, uid_validity, skipped],
+                |row| Ok(row.get::<_, i64>(0)? as u32),
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(uids)
+    }
+
+    /// Mailboxes of the account with failed messages that aren't skipped.
+    pub fn get_failed_mailboxes(&self, account_email: &str) -> Result<HashSet<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT mailbox FROM fetch_failures
+             WHERE account_email = ?1 AND skipped_at IS NULL",
+        )?;
+        let mailboxes = stmt
+            .query_map(params![account_email], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(mailboxes)
+    }
+
+    /// A page of failed messages, most recently failed first. Also returns the total number
+    /// of failures.
+    pub fn get_fetch_failures(
+        &self,
+        limit: i64,
+        offset: i64,
+        include_skipped: bool,
+    ) -> Result<(i64, Vec<FetchFailure>)> {
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM fetch_failures WHERE ?1 OR skipped_at IS NULL",
+            params![include_skipped],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, account_email, mailbox, uid_validity, uid, error_class, error, attempts,
+                    first_failed_at, last_failed_at, skipped_at
+             FROM fetch_failures
+             WHERE ?1 OR skipped_at IS NULL
+             ORDER BY last_failed_at DESC, id DESC
+             LIMIT ?2 OFFSET ?3",
+        )?;
+        let failures = stmt
+            .query_map(params![include_skipped, limit, offset], |row| {
+                Ok(FetchFailure {
+                    id: row.get(0)?,
+                    account_email: row.get(1)?,
+                    mailbox: row.get(2)?,
+                    uid_validity: row.get::<_, i64>(3)? as u32,
+                    uid: row.get::<_, i64>(4)? as u32,
+                    error_class: row.get(5)?,
+                    error: row.get(6)?,
+                    attempts: row.get(7)?,
+                    first_failed_at: parse_timestamp(row.get(8)?),
+                    last_failed_at: parse_timestamp(row.get(9)?),
+                    skipped_at: parse_timestamp(row.get(10)?),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok((total, failures))
+    }
+
+    /// Marks a failed message as permanently skipped, or makes it eligible for fetching
+    /// again. Returns false if there is no such failure.
+    pub fn set_fetch_failure_skipped(&self, id: i64, skipped: bool) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE fetch_failures
+             SET skipped_at = CASE WHEN ?1 THEN COALESCE(skipped_at, ?2) END
+             WHERE id = ?3",
+            params![skipped, Utc::now().to_rfc3339(), id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Status of the latest run. Runs triggered by IDLE are not what the dashboard's fetch
+    /// button started and run next to it, so they are skipped.
     pub fn get_latest_fetch_status(&self) -> Result<Option<FetchStatus>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT started_at, completed_at, messages_fetched, status
              FROM fetch_history
-             ORDER BY started_at DESC
+             WHERE triggered_by IS NOT 'idle'
+             ORDER BY id DESC
              LIMIT 1",
         )?;
 
@@ -197,11 +1539,13 @@ impl Database {
                 .ok()
                 .map(|dt| dt.with_timezone(&Utc));
 
-            let is_running = completed_at_str.is_none() && status == "running";
+            let is_running =
+                completed_at_str.is_none() && status == FetchRunStatus::Running.as_str();
 
             Ok(FetchStatus {
                 is_running,
                 started_at,
+                completed_at: parse_timestamp(completed_at_str),
                 messages_fetched,
             })
         })?;
@@ -213,3 +1557,292 @@ impl Database {
         }
     }
 }
+
+/// Inserts the `fetched_emails` row of a message. A Gmail message also gets its
+/// `gmail_messages` row (unless another label already added it) and its current labels.
+fn insert_fetched_email(
+    conn: &Connection,
+    account_email: &str,
+    mailbox: &str,
+    uid_validity: u32,
+    message: &FetchedMessage,
+) -> Result<()> {
+    let now = Utc::now().to_rfc3339();
+    let msgid = message.gmail.map(|(msgid, _)| msgid as i64);
+    if let Some((_, labels)) = message.gmail {
+        conn.execute(
+            "INSERT OR IGNORE INTO gmail_messages
+             (account_email, gmail_msgid, file_path, size_bytes, fetched_at, sha256)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                account_email,
+                msgid,
+                message.file_path.to_string_lossy(),
+                message.size_bytes as i64,
+                now,
+                message.sha256
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM gmail_labels WHERE account_email = ?1 AND gmail_msgid = ?2",
+            params![account_email, msgid],
+        )?;
+        for label in labels {
+            conn.execute(
+                "INSERT OR IGNORE INTO gmail_labels (account_email, gmail_msgid, label)
+                 VALUES (?1, ?2, ?3)",
+                params![account_email, msgid, label],
+            )?;
+        }
+    }
+    conn.execute(
+        "INSERT OR REPLACE INTO fetched_emails
+         (account_email, mailbox, uid_validity, uid, file_path, size_bytes, fetched_at,
+          gmail_msgid, sha256, flags, internal_date)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            account_email,
+            mailbox,
+            uid_validity,
+            message.uid,
+            message.file_path.to_string_lossy(),
+            message.size_bytes as i64,
+            now,
+            msgid,
+            message.sha256,
+            message.attributes.flags,
+            message
+                .attributes
+                .internal_date
+                .map(|date| date.to_rfc3339())
+        ],
+    )?;
+    Ok(())
+}
+
+fn parse_timestamp(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Counts a finished run in its bucket of `fetch_run_durations`.
+fn count_run_duration(conn: &Connection, duration: chrono::Duration) -> Result<()> {
+    let seconds = duration.num_milliseconds().max(0) as f64 / 1000.0;
+    let upper_bound = RUN_DURATION_BUCKETS
+        .iter()
+        .copied()
+        .find(|bound| seconds <= *bound)
+        .unwrap_or(f64::INFINITY);
+    conn.execute(
+        "INSERT INTO fetch_run_durations (upper_bound, runs, total_seconds) VALUES (?1, 1, ?2)
+         ON CONFLICT(upper_bound) DO UPDATE SET
+            runs = runs + 1,
+            total_seconds = total_seconds + excluded.total_seconds",
+        params![upper_bound, seconds],
+    )?;
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Result<Vec<String>, _> = stmt.query_map([], |row| row.get(1))?.collect();
+    Ok(columns?.iter().any(|c| c == column))
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// A database file of its own for a test, removed when dropped.
+#[cfg(test)]
+pub struct TestDb(pub PathBuf);
+
+#[cfg(test)]
+impl TestDb {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "courrier-db-{}-{}.sqlite",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        TestDb(path)
+    }
+
+    pub fn open(&self) -> Database {
+        Database::new(self.0.to_str().unwrap()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(db: &Database, mailbox: &str, uid_validity: u32, uids: &[u32]) {
+        let attributes = MessageAttributes::default();
+        let path = PathBuf::from("INBOX/1.eml");
+        let messages: Vec<FetchedMessage> = uids
+            .iter()
+            .map(|uid| FetchedMessage {
+                uid: *uid,
+                file_path: &path,
+                size_bytes: 10,
+                sha256: None,
+                attributes: &attributes,
+                gmail: None,
+            })
+            .collect();
+        db.record_fetched_messages("me@example.com", mailbox, uid_validity, &messages)
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_the_original_schema() {
+        let test_db = TestDb::new("migrate");
+        {
+            // The schema before UIDVALIDITY tracking and run history
+            let conn = Connection::open(&test_db.0).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE fetched_emails (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    account_email TEXT NOT NULL,
+                    mailbox TEXT NOT NULL,
+                    uid INTEGER NOT NULL,
+                    file_path TEXT NOT NULL,
+                    size_bytes INTEGER NOT NULL,
+                    fetched_at TEXT NOT NULL,
+                    UNIQUE(account_email, mailbox, uid)
+                 );
+                 CREATE TABLE fetch_history (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    account_email TEXT NOT NULL,
+                    mailbox TEXT NOT NULL,
+                    started_at TEXT NOT NULL,
+                    completed_at TEXT,
+                    messages_fetched INTEGER NOT NULL DEFAULT 0,
+                    status TEXT NOT NULL DEFAULT 'running'
+                 );
+                 CREATE INDEX idx_fetched_emails_lookup
+                    ON fetched_emails(account_email, mailbox, uid);
+                 INSERT INTO fetched_emails
+                    (account_email, mailbox, uid, file_path, size_bytes, fetched_at)
+                    VALUES ('me@example.com', 'INBOX', 1, 'INBOX/1.eml', 10,
+                            '2024-01-01T00:00:00+00:00');",
+            )
+            .unwrap();
+        }
+
+        let db = test_db.open();
+        // Existing rows belong to the unknown generation 0, and the same UID can now be
+        // recorded in another one
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 0).unwrap(),
+            [1]
+        );
+        record(&db, "INBOX", 7, &[1]);
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 7).unwrap(),
+            [1]
+        );
+        let conn = db.conn.lock().unwrap();
+        for (table, column) in [
+            ("fetched_emails", "sha256"),
+            ("fetched_emails", "flags"),
+            ("fetched_emails", "deleted_on_server_at"),
+            ("mailbox_state", "highest_modseq"),
+            ("fetch_history", "messages_failed"),
+            ("fetch_history_mailboxes", "error_class"),
+            ("fetch_run_durations", "upper_bound"),
+        ] {
+            assert!(
+                has_column(&conn, table, column).unwrap(),
+                "{table}.{column}"
+            );
+        }
+        drop(conn);
+
+        // Reopening an up-to-date database changes nothing
+        drop(db);
+        let db = test_db.open();
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 0).unwrap(),
+            [1]
+        );
+    }
+
+    #[test]
+    fn records_runs_and_interrupts_unfinished_ones() {
+        let test_db = TestDb::new("runs");
+        let db = test_db.open();
+
+        let run = db.start_fetch_run(None, None, FetchTrigger::Cli).unwrap();
+        let inbox = db
+            .start_mailbox_fetch(run, "me@example.com", Some("INBOX"))
+            .unwrap();
+        let counts = FetchCounts {
+            messages_fetched: 2,
+            bytes_fetched: 20,
+            ..FetchCounts::default()
+        };
+        db.finish_mailbox_fetch(inbox, &counts, None).unwrap();
+        let sent = db
+            .start_mailbox_fetch(run, "me@example.com", Some("Sent"))
+            .unwrap();
+        db.finish_mailbox_fetch(sent, &FetchCounts::default(), Some(("network", "reset")))
+            .unwrap();
+        assert_eq!(
+            db.finish_fetch_run(run, false).unwrap().as_str(),
+            "completed_with_errors"
+        );
+        assert_eq!(
+            db.get_fetch_errors().unwrap(),
+            [("me@example.com".to_string(), "network".to_string(), 1)]
+        );
+        let durations = db.get_fetch_run_durations().unwrap();
+        assert_eq!(durations.len(), 1);
+        assert_eq!(durations[0].0, RUN_DURATION_BUCKETS[0]);
+        assert_eq!(durations[0].1, 1);
+
+        let unfinished = db.start_fetch_run(None, None, FetchTrigger::Cli).unwrap();
+        db.start_mailbox_fetch(unfinished, "me@example.com", Some("INBOX"))
+            .unwrap();
+        assert_eq!(db.interrupt_unfinished_runs().unwrap(), 1);
+        let (total, runs) = db.get_fetch_history(10, 0).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(runs[0].status, "interrupted");
+        assert_eq!(runs[0].mailboxes[0].status, "interrupted");
+        assert_eq!(runs[1].status, "completed_with_errors");
+        assert_eq!(runs[1].messages_fetched, 2);
+
+        // The dashboard's status skips runs started by IDLE
+        let idle = db
+            .start_fetch_run(Some("me@example.com"), Some("INBOX"), FetchTrigger::Idle)
+            .unwrap();
+        let inbox = db
+            .start_mailbox_fetch(idle, "me@example.com", Some("INBOX"))
+            .unwrap();
+        db.finish_mailbox_fetch(inbox, &counts, None).unwrap();
+        db.finish_fetch_run(idle, false).unwrap();
+        let latest = db.get_latest_fetch_status().unwrap().unwrap();
+        assert!(latest.completed_at.is_none());
+        assert_eq!(latest.messages_fetched, 0);
+        let (_, runs) = db.get_fetch_history(1, 0).unwrap();
+        assert_eq!(runs[0].id, idle);
+        assert_eq!(runs[0].triggered_by.as_deref(), Some("idle"));
+        assert_eq!(runs[0].account_email.as_deref(), Some("me@example.com"));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::config::{AccountConfig, DeletionPolicy};
+use crate::database::Database;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// Directory under `email_storage_path` that the `mirror` policy moves messages to once they
+/// are deleted on the server. Mirrors the layout of the archive below it.
+const TRASH_DIR: &str = ".trash";
+
+/// Changes found by comparing a mailbox's archived UIDs with those on the server.
+#[derive(Debug, Default)]
+pub struct DeletionSync {
+    pub deleted: usize,
+    pub restored: usize,
+    pub moved_to_trash: usize,
+}
+
+/// Marks archived messages of the mailbox that are missing from `server_uids` as deleted on
+/// the server and clears the mark of those that are back. With the `mirror` policy the files
+/// of deleted messages are moved to the trash area (and back, if they reappear).
+#[allow(clippy::too_many_arguments)]
+pub fn sync_mailbox_deletions(
+    db: &Database,
+    config: &AccountConfig,
+    output_dir: &Path,
+    mailbox: &str,
+    uid_validity: u32,
+    archived_uids: &HashSet<u32>,
+    deleted_uids: &HashSet<u32>,
+    server_uids: &HashSet<u32>,
+) -> Result<DeletionSync> {
+    let mut newly_deleted: Vec<u32> = archived_uids
+        .iter()
+        .filter(|uid| !server_uids.contains(uid) && !deleted_uids.contains(uid))
+        .copied()
+        .collect();
+    newly_deleted.sort_unstable();
+    let mut restored: Vec<u32> = deleted_uids
+        .iter()
+        .filter(|uid| server_uids.contains(uid))
+        .copied()
+        .collect();
+    restored.sort_unstable();
+
+    let mut sync = DeletionSync {
+        deleted: newly_deleted.len(),
+        restored: restored.len(),
+        moved_to_trash: 0,
+    };
+
+    let deleted_files =
+        db.set_deleted_on_server(&config.email, mailbox, uid_validity, &newly_deleted, true)?;
+    let restored_files =
+        db.set_deleted_on_server(&config.email, mailbox, uid_validity, &restored, false)?;
+
+    if config.deletion_policy == DeletionPolicy::Mirror {
+        let trash = output_dir.join(TRASH_DIR);
+        // A file shared with mailboxes where the message still exists (Gmail labels, blob
+        // store) stays until the last of them is gone
+        for file_path in deleted_files {
+            if db.count_live_references(&file_path)? == 0 {
+                if let Ok(relative) = Path::new(&file_path).strip_prefix(output_dir) {
+                    move_file(db, &file_path, &trash.join(relative))?;
+                    sync.moved_to_trash += 1;
+                }
+            }
+        }
+        for file_path in restored_files {
+            if let Ok(relative) = Path::new(&file_path).strip_prefix(&trash) {
+                move_file(db, &file_path, &output_dir.join(relative))?;
+            }
+        }
+    }
+
+    Ok(sync)
+}
+
+/// Applies `purge-after`: deletes the archived copies of messages that were deleted on the
+/// server longer ago than the retention period. Returns the number of purged rows.
+pub fn purge_expired(db: &Database, config: &AccountConfig) -> Result<usize> {
+    let DeletionPolicy::PurgeAfter(period) = config.deletion_policy else {
+        return Ok(0);
+    };
+
+    let (purged, files) = db.purge_deleted_messages(&config.email, Utc::now() - period.0)?;
+    for file_path in files {
+        match fs::remove_file(&file_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!(path = %file_path, error = %e, "Failed to delete purged message"),
+        }
+    }
+
+    Ok(purged)
+}
+
+fn move_file(db: &Database, file_path: &str, target: &Path) -> Result<()> {
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::rename(file_path, target)
+        .with_context(|| format!("Failed to move {} to {}", file_path, target.display()))?;
+    db.move_message_file(file_path, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetentionPeriod;
+    use crate::database::{FetchedMessage, MessageAttributes, TestDb};
+    use std::path::PathBuf;
+
+    /// An empty archive directory of its own for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "courrier-deletions-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Records `file` as message `uid` of `mailbox`, writing it first if needed.
+    fn archive(db: &Database, mailbox: &str, uid: u32, file: &Path) {
+        if !file.exists() {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "Subject: test\r\n\r\nbody\r\n").unwrap();
+        }
+        let message = FetchedMessage {
+            uid,
+            file_path: file,
+            size_bytes: 10,
+            sha256: None,
+            attributes: &MessageAttributes::default(),
+            gmail: None,
+        };
+        db.record_fetched_messages("me@example.com", mailbox, 1, &[message])
+            .unwrap();
+    }
+
+    fn uids(uids: &[u32]) -> HashSet<u32> {
+        uids.iter().copied().collect()
+    }
+
+    #[test]
+    fn mirror_moves_files_once_no_mailbox_has_them() {
+        let test_db = TestDb::new("deletions-mirror");
+        let db = test_db.open();
+        let dir = test_dir("mirror");
+        let mut config = AccountConfig::for_test("me@example.com");
+        config.deletion_policy = DeletionPolicy::Mirror;
+
+        // The first message is in two mailboxes with one file, like a Gmail label
+        let shared = dir.join("me_example.com").join("INBOX").join("1.eml");
+        let single = dir.join("me_example.com").join("INBOX").join("2.eml");
+        archive(&db, "INBOX", 1, &shared);
+        archive(&db, "Archive", 7, &shared);
+        archive(&db, "INBOX", 2, &single);
+
+        let sync = sync_mailbox_deletions(
+            &db,
+            &config,
+            &dir,
+            "INBOX",
+            1,
+            &uids(&[1, 2]),
+            &uids(&[]),
+            &uids(&[]),
+        )
+        .unwrap();
+        assert_eq!(sync.deleted, 2);
+        assert_eq!(sync.moved_to_trash, 1);
+        assert!(shared.exists());
+        assert!(!single.exists());
+        let trashed = dir.join(TRASH_DIR).join("me_example.com/INBOX/2.eml");
+        assert!(trashed.exists());
+        assert!(db
+            .get_archived_file_paths()
+            .unwrap()
+            .contains(&trashed.to_string_lossy().to_string()));
+
+        // Gone from the last mailbox too
+        let sync = sync_mailbox_deletions(
+            &db,
+            &config,
+            &dir,
+            "Archive",
+            1,
+            &uids(&[7]),
+            &uids(&[]),
+            &uids(&[]),
+        )
+        .unwrap();
+        assert_eq!(sync.moved_to_trash, 1);
+        assert!(!shared.exists());
+
+        // A message that reappears is moved back
+        let sync = sync_mailbox_deletions(
+            &db,
+            &config,
+            &dir,
+            "INBOX",
+            1,
+            &uids(&[1, 2]),
+            &uids(&[1, 2]),
+            &uids(&[2]),
+        )
+        .unwrap();
+        assert_eq!(sync.restored, 1);
+        assert!(single.exists());
+        assert!(!trashed.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn purges_only_expired_messages() {
+        let test_db = TestDb::new("deletions-purge");
+        let db = test_db.open();
+        let dir = test_dir("purge");
+        let mut config = AccountConfig::for_test("me@example.com");
+        config.deletion_policy =
+            DeletionPolicy::PurgeAfter(RetentionPeriod::try_from("30d".to_string()).unwrap());
+
+        let inbox = dir.join("me_example.com").join("INBOX");
+        for uid in 1..=3 {
+            archive(&db, "INBOX", uid, &inbox.join(format!("{}.eml", uid)));
+        }
+        // 1 was deleted long ago, 2 just now and 3 is still on the server
+        sync_mailbox_deletions(
+            &db,
+            &config,
+            &dir,
+            "INBOX",
+            1,
+            &uids(&[1, 2, 3]),
+            &uids(&[]),
+            &uids(&[3]),
+        )
+        .unwrap();
+        rusqlite::Connection::open(&test_db.0)
+            .unwrap()
+            .execute(
+                "UPDATE fetched_emails SET deleted_on_server_at = ?1 WHERE uid = 1",
+                [(Utc::now() - chrono::Duration::days(31)).to_rfc3339()],
+            )
+            .unwrap();
+
+        assert_eq!(purge_expired(&db, &config).unwrap(), 1);
+        assert!(!inbox.join("1.eml").exists());
+        assert!(inbox.join("2.eml").exists());
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 1).unwrap(),
+            [2, 3]
+        );
+
+        // Other policies never purge
+        config.deletion_policy = DeletionPolicy::Keep;
+        assert_eq!(purge_expired(&db, &config).unwrap(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
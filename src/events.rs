@@ -0,0 +1,139 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber. A subscriber that falls further behind skips the oldest
+/// ones instead of slowing down the fetch.
+const EVENT_BUFFER: usize = 1024;
+
+/// Progress of a fetch run, published by the fetcher and streamed to the dashboard over
+/// `/api/events`. Serialized with a `type` field naming the variant.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FetchEvent {
+    RunStarted {
+        run_id: i64,
+        accounts: Vec<String>,
+    },
+    AccountStarted {
+        run_id: i64,
+        account: String,
+    },
+    /// The mailbox was selected and the messages to download are known
+    MailboxSelected {
+        run_id: i64,
+        account: String,
+        mailbox: String,
+        exists: u32,
+        to_fetch: usize,
+    },
+    MessageSaved {
+        run_id: i64,
+        account: String,
+        mailbox: String,
+        uid: u32,
+        size_bytes: usize,
+        done: usize,
+        total: usize,
+    },
+    MessageFailed {
+        run_id: i64,
+        account: String,
+        mailbox: String,
+        uid: u32,
+        error: String,
+        done: usize,
+        total: usize,
+    },
+    MailboxFinished {
+        run_id: i64,
+        account: String,
+        mailbox: String,
+        messages_fetched: usize,
+        messages_failed: usize,
+        error: Option<String>,
+    },
+    AccountFinished {
+        run_id: i64,
+        account: String,
+        messages_fetched: usize,
+        error: Option<String>,
+    },
+    RunFinished {
+        run_id: i64,
+        status: &'static str,
+        messages_fetched: usize,
+    },
+}
+
+/// Broadcasts fetch events to every subscriber. Cheap to clone; publishing without
+/// subscribers (e.g. `courrier fetch`) drops the event.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<FetchEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { sender }
+    }
+
+    pub fn publish(&self, event: FetchEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FetchEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Publishes the per-message events of one mailbox download, counting messages as they
+/// complete.
+#[derive(Debug, Clone)]
+pub struct MailboxProgress {
+    events: EventBus,
+    run_id: i64,
+    account: String,
+    mailbox: String,
+    total: usize,
+    done: usize,
+}
+
+impl MailboxProgress {
+    pub fn new(events: &EventBus, run_id: i64, account: &str, mailbox: &str, total: usize) -> Self {
+        MailboxProgress {
+            events: events.clone(),
+            run_id,
+            account: account.to_string(),
+            mailbox: mailbox.to_string(),
+            total,
+            done: 0,
+        }
+    }
+
+    pub fn saved(&mut self, uid: u32, size_bytes: usize) {
+        self.done += 1;
+        self.events.publish(FetchEvent::MessageSaved {
+            run_id: self.run_id,
+            account: self.account.clone(),
+            mailbox: self.mailbox.clone(),
+            uid,
+            size_bytes,
+            done: self.done,
+            total: self.total,
+        });
+    }
+
+    pub fn failed(&mut self, uid: u32, error: &anyhow::Error) {
+        self.done += 1;
+        self.events.publish(FetchEvent::MessageFailed {
+            run_id: self.run_id,
+            account: self.account.clone(),
+            mailbox: self.mailbox.clone(),
+            uid,
+            error: format!("{:#}", error),
+            done: self.done,
+            total: self.total,
+        });
+    }
+}
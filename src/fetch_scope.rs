@@ -0,0 +1,228 @@
+use crate::config::AccountConfig;
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::fmt;
+
+/// Limits a fetch to one account, the mailboxes matching a pattern, a date range and/or the
+/// messages that failed before. The default scope covers everything, like a scheduled fetch.
+///
+/// Accepted as the JSON body of `POST /api/fetch` and as `courrier fetch` flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchScope {
+    /// Email address of the only account to fetch
+    pub account: Option<String>,
+    /// Mailbox name or pattern (same syntax as `include_mailboxes`). Applies on top of the
+    /// configured mailbox filters.
+    pub mailbox: Option<String>,
+    /// Only messages with an INTERNALDATE on or after this day (`YYYY-MM-DD`)
+    pub since: Option<NaiveDate>,
+    /// Only messages with an INTERNALDATE before this day (`YYYY-MM-DD`)
+    pub before: Option<NaiveDate>,
+    /// Only retry messages recorded in `fetch_failures` that aren't skipped
+    #[serde(default)]
+    pub failed_only: bool,
+}
+
+impl FetchScope {
+    /// Parses `--account`, `--mailbox`, `--since`, `--before` and `--failed-only` from the
+    /// arguments following `courrier fetch`.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut scope = FetchScope::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--failed-only" {
+                scope.failed_only = true;
+                continue;
+            }
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, value.to_string()),
+                None => (
+                    flag.as_str(),
+                    args.next()
+                        .with_context(|| format!("Missing value for {}", flag))?
+                        .clone(),
+                ),
+            };
+            match flag {
+                "--account" => scope.account = Some(value),
+                "--mailbox" => scope.mailbox = Some(value),
+                "--since" => scope.since = Some(parse_date(&value)?),
+                "--before" => scope.before = Some(parse_date(&value)?),
+                _ => return Err(anyhow::anyhow!("Unknown fetch option: {}", flag)),
+            }
+        }
+        scope.validate()?;
+        Ok(scope)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let (Some(since), Some(before)) = (self.since, self.before) {
+            if since >= before {
+                return Err(anyhow::anyhow!(
+                    "--since ({}) must be before --before ({})",
+                    since,
+                    before
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The accounts this scope covers. Fails if the requested account isn't configured.
+    pub fn select_accounts(&self, accounts: &[AccountConfig]) -> Result<Vec<AccountConfig>> {
+        let Some(email) = &self.account else {
+            return Ok(accounts.to_vec());
+        };
+        let selected: Vec<AccountConfig> = accounts
+            .iter()
+            .filter(|account| account.email.eq_ignore_ascii_case(email))
+            .cloned()
+            .collect();
+        if selected.is_empty() {
+            return Err(anyhow::anyhow!("No configured account {}", email));
+        }
+        Ok(selected)
+    }
+
+    /// IMAP SEARCH criteria for the date range, e.g. `SINCE 1-Jan-2024 BEFORE 1-Feb-2024`.
+    pub fn search_criteria(&self) -> Option<String> {
+        let criteria: Vec<String> = [("SINCE", self.since), ("BEFORE", self.before)]
+            .into_iter()
+            .filter_map(|(key, date)| Some(format!("{} {}", key, date?.format("%-d-%b-%Y"))))
+            .collect();
+        (!criteria.is_empty()).then(|| criteria.join(" "))
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.account.is_none()
+            && self.mailbox.is_none()
+            && self.since.is_none()
+            && self.before.is_none()
+            && !self.failed_only
+    }
+}
+
+impl fmt::Display for FetchScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_everything() {
+            return write!(f, "all accounts and mailboxes");
+        }
+        let mut parts = Vec::new();
+        if let Some(account) = &self.account {
+            parts.push(format!("account {}", account));
+        }
+        if let Some(mailbox) = &self.mailbox {
+            parts.push(format!("mailbox {}", mailbox));
+        }
+        if let Some(since) = self.since {
+            parts.push(format!("since {}", since));
+        }
+        if let Some(before) = self.before {
+            parts.push(format!("before {}", before));
+        }
+        if self.failed_only {
+            parts.push("failed messages only".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {} (expected YYYY-MM-DD)", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("2024-01-31").unwrap(), date(2024, 1, 31));
+        assert_eq!(parse_date("2024-2-9").unwrap(), date(2024, 2, 9));
+        assert!(parse_date("2024-02-30").is_err());
+        assert!(parse_date("31-01-2024").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn parses_flags_with_and_without_equals() {
+        let scope = FetchScope::from_args(&args(&[
+            "--account",
+            "me@example.com",
+            "--mailbox=INBOX",
+            "--since=2024-01-01",
+            "--before",
+            "2024-02-01",
+            "--failed-only",
+        ]))
+        .unwrap();
+        assert_eq!(scope.account.as_deref(), Some("me@example.com"));
+        assert_eq!(scope.mailbox.as_deref(), Some("INBOX"));
+        assert_eq!(scope.since, Some(date(2024, 1, 1)));
+        assert_eq!(scope.before, Some(date(2024, 2, 1)));
+        assert!(scope.failed_only);
+    }
+
+    #[test]
+    fn rejects_bad_flags_and_ranges() {
+        assert!(FetchScope::from_args(&args(&["--since"])).is_err());
+        assert!(FetchScope::from_args(&args(&["--since", "2024-13-01"])).is_err());
+        assert!(FetchScope::from_args(&args(&["--until", "2024-01-01"])).is_err());
+        assert!(
+            FetchScope::from_args(&args(&["--since=2024-02-01", "--before=2024-02-01"])).is_err()
+        );
+    }
+
+    #[test]
+    fn formats_search_criteria_as_imap_dates() {
+        let scope = FetchScope {
+            since: Some(date(2024, 1, 5)),
+            before: Some(date(2024, 12, 25)),
+            ..FetchScope::default()
+        };
+        assert_eq!(
+            scope.search_criteria().as_deref(),
+            Some("SINCE 5-Jan-2024 BEFORE 25-Dec-2024")
+        );
+        assert_eq!(FetchScope::default().search_criteria(), None);
+    }
+
+    #[test]
+    fn selects_accounts_case_insensitively() {
+        let accounts = [
+            AccountConfig::for_test("a@example.com"),
+            AccountConfig::for_test("b@example.com"),
+        ];
+        let scope = FetchScope {
+            account: Some("B@Example.com".to_string()),
+            ..FetchScope::default()
+        };
+        let selected = scope.select_accounts(&accounts).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].email, "b@example.com");
+        assert_eq!(
+            FetchScope::default()
+                .select_accounts(&accounts)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let scope = FetchScope {
+            account: Some("c@example.com".to_string()),
+            ..FetchScope::default()
+        };
+        assert!(scope.select_accounts(&accounts).is_err());
+    }
+}
@@ -1,24 +1,52 @@
-use crate::config::AccountConfig;
-use crate::database::Database;
+use crate::atomic_file;
+use crate::blob_store::{self, BlobStore};
+use crate::config::{AccountConfig, AuthMethod, SaslMechanism};
+use crate::connection::{self, ImapSession, RawChannel};
+use crate::control::FetchControl;
+use crate::database::{
+    self, Database, FetchCounts, FetchTrigger, FetchedMessage, MailboxState, MessageAttributes,
+};
+use crate::deletions;
+use crate::events::{EventBus, FetchEvent, MailboxProgress};
+use crate::fetch_scope::FetchScope;
+use crate::gmail::{self, GmailMetadata};
+use crate::mailbox_filter::MailboxFilter;
+use crate::oauth;
+use crate::retry::{retry, AuthError, ErrorClass};
 use anyhow::Result;
-use imap::Session;
-use native_tls::TlsStream;
+use futures::stream::{self, StreamExt};
+use imap::types::NameAttribute;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Write;
-use std::net::TcpStream;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+/// Directory under an account's folder holding Gmail messages, one `<X-GM-MSGID>.eml` each.
+pub const GMAIL_STORE_DIR: &str = "gmail-messages";
+
+/// Prefix of the directory under a mailbox's folder that a previous UIDVALIDITY generation
+/// is moved to, e.g. `.uidvalidity-1234`.
+pub const UID_VALIDITY_DIR_PREFIX: &str = ".uidvalidity-";
+
+/// Archived UIDs per `UID FETCH` when re-syncing their flags.
+const FLAGS_FETCH_CHUNK: usize = 1000;
 
 fn fetch_message_body(
-    session: &mut Session<TlsStream<TcpStream>>,
+    session: &mut ImapSession,
     uid: u32,
     use_uid_fetch: bool,
-) -> Result<Vec<u8>> {
+) -> Result<(Vec<u8>, MessageAttributes)> {
     // Try BODY.PEEK[] first (most reliable, doesn't mark as seen)
     let body = if use_uid_fetch {
-        match session.uid_fetch(uid.to_string(), "BODY.PEEK[]") {
+        match session.uid_fetch(uid.to_string(), "(FLAGS INTERNALDATE BODY.PEEK[])") {
             Ok(msgs) => {
                 if let Some(msg) = msgs.iter().next() {
-                    msg.body().map(Vec::from)
+                    msg.body()
+                        .map(|body| (Vec::from(body), message_attributes(msg)))
                 } else {
                     None
                 }
@@ -26,10 +54,11 @@ fn fetch_message_body(
             Err(_) => None, // Will try RFC822 as fallback
         }
     } else {
-        match session.fetch(uid.to_string(), "BODY.PEEK[]") {
+        match session.fetch(uid.to_string(), "(FLAGS INTERNALDATE BODY.PEEK[])") {
             Ok(msgs) => {
                 if let Some(msg) = msgs.iter().next() {
-                    msg.body().map(Vec::from)
+                    msg.body()
+                        .map(|body| (Vec::from(body), message_attributes(msg)))
                 } else {
                     None
                 }
@@ -45,16 +74,16 @@ fn fetch_message_body(
 
     // BODY.PEEK[] didn't work (either failed or returned no body), try RFC822
     let rfc822_result = if use_uid_fetch {
-        session.uid_fetch(uid.to_string(), "RFC822")
+        session.uid_fetch(uid.to_string(), "(FLAGS INTERNALDATE RFC822)")
     } else {
-        session.fetch(uid.to_string(), "RFC822")
+        session.fetch(uid.to_string(), "(FLAGS INTERNALDATE RFC822)")
     };
 
     match rfc822_result {
         Ok(msgs) => {
             if let Some(msg) = msgs.iter().next() {
                 if let Some(body) = msg.body() {
-                    Ok(Vec::from(body))
+                    Ok((Vec::from(body), message_attributes(msg)))
                 } else {
                     Err(anyhow::anyhow!(
                         "Failed to fetch message body for UID {}: BODY.PEEK[] and RFC822 both returned no body",
@@ -68,278 +97,1790 @@ fn fetch_message_body(
                 ))
             }
         }
-        Err(e) => Err(anyhow::anyhow!(
-            "Failed to fetch message body for UID {}: BODY.PEEK[] and RFC822 both failed. Last error: {:?}",
-            uid,
-            e
-        )),
+        Err(e) => Err(anyhow::Error::from(e).context(format!(
+            "Failed to fetch message body for UID {}: BODY.PEEK[] and RFC822 both failed",
+            uid
+        ))),
     }
 }
 
-pub async fn fetch_all_messages_from_mailbox(
+fn message_attributes(msg: &imap::types::Fetch) -> MessageAttributes {
+    MessageAttributes::new(msg.flags(), msg.internal_date())
+}
+
+/// An authenticated IMAP session that is reused for every mailbox of an account.
+pub struct AccountSession {
+    session: ImapSession,
+    /// QRESYNC implies CONDSTORE, either one lets us ask for changes since the last run
+    condstore: bool,
+    /// Raw channel for X-GM-* fetches, set when the server supports Gmail's extensions
+    gmail: Option<RawChannel>,
+}
+
+impl AccountSession {
+    fn connect(config: &AccountConfig) -> Result<Self> {
+        let (mut session, raw) = connect_and_login_sync(config)?;
+        let capabilities = session.capabilities()?;
+        let condstore = capabilities.has_str("CONDSTORE") || capabilities.has_str("QRESYNC");
+        let gmail = capabilities.has_str(gmail::CAPABILITY).then_some(raw);
+        drop(capabilities);
+        Ok(AccountSession {
+            session,
+            condstore,
+            gmail,
+        })
+    }
+
+    fn logout(mut self) {
+        // Logout (ignore errors)
+        let _ = self.session.logout();
+    }
+}
+
+/// `spawn_blocking` keeping the current span, so the IMAP work done on the blocking thread is
+/// logged with the run, account and mailbox it belongs to.
+fn spawn_blocking_in_span<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+}
+
+async fn open_account_session(config: &AccountConfig) -> Result<AccountSession> {
+    let config_clone = config.clone();
+    spawn_blocking_in_span(move || AccountSession::connect(&config_clone)).await?
+}
+
+async fn close_account_session(imap: AccountSession) {
+    let _ = spawn_blocking_in_span(move || imap.logout()).await;
+}
+
+/// A fetch run in progress: the `fetch_history` row it is recorded in, what it covers, where
+/// it reports progress, the control that can pause or cancel it, and the span its logs are
+/// recorded under.
+struct RunContext {
+    run_id: i64,
+    scope: FetchScope,
+    events: EventBus,
+    control: FetchControl,
+    span: Span,
+}
+
+impl RunContext {
+    /// Records a new run and announces it on the event bus.
+    fn start(
+        db: &Database,
+        scope: FetchScope,
+        trigger: FetchTrigger,
+        events: &EventBus,
+        control: FetchControl,
+        accounts: Vec<String>,
+    ) -> Result<Self> {
+        let run_id =
+            db.start_fetch_run(scope.account.as_deref(), scope.mailbox.as_deref(), trigger)?;
+        events.publish(FetchEvent::RunStarted { run_id, accounts });
+        Ok(RunContext {
+            run_id,
+            scope,
+            events: events.clone(),
+            control,
+            span: info_span!("fetch_run", run_id),
+        })
+    }
+
+    fn finish(&self, db: &Database, messages_fetched: usize) -> Result<()> {
+        let status = db.finish_fetch_run(self.run_id, self.control.is_cancelled())?;
+        self.events.publish(FetchEvent::RunFinished {
+            run_id: self.run_id,
+            status: status.as_str(),
+            messages_fetched,
+        });
+        Ok(())
+    }
+}
+
+/// The lock of a mailbox, held while it is synced. Fetches started by IDLE run next to the
+/// scheduled and manual ones, and wait here if both get to the same mailbox.
+fn mailbox_lock(account_email: &str, mailbox_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+    type Locks = HashMap<(String, String), Arc<tokio::sync::Mutex<()>>>;
+    static LOCKS: OnceLock<Mutex<Locks>> = OnceLock::new();
+    LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .entry((account_email.to_string(), mailbox_name.to_string()))
+        .or_default()
+        .clone()
+}
+
+/// Runs `sync_mailbox` as an entry of `run`.
+async fn sync_mailbox_recorded(
+    imap: &mut Option<AccountSession>,
     config: &AccountConfig,
     mailbox_name: &str,
     output_dir: &Path,
     db: &Database,
-) -> Result<usize> {
-    // Get already fetched UIDs from database first (before blocking task)
-    let fetched_uids = db.get_fetched_uids(&config.email, mailbox_name)?;
-    let fetched_set: std::collections::HashSet<u32> = fetched_uids.into_iter().collect();
+    run: &RunContext,
+) -> Result<FetchCounts> {
+    let lock = mailbox_lock(&config.email, mailbox_name);
+    let _syncing = lock.lock().await;
+    let entry_id = db.start_mailbox_fetch(run.run_id, &config.email, Some(mailbox_name))?;
+    let result = sync_mailbox(imap, config, mailbox_name, output_dir, db, run)
+        .instrument(info_span!("mailbox", mailbox = %mailbox_name))
+        .await;
+    let (counts, error) = match &result {
+        Ok(counts) => (*counts, None),
+        Err(e) => (
+            FetchCounts::default(),
+            Some((ErrorClass::of(e), format!("{:#}", e))),
+        ),
+    };
+    db.finish_mailbox_fetch(
+        entry_id,
+        &counts,
+        error
+            .as_ref()
+            .map(|(class, message)| (class.as_str(), message.as_str())),
+    )?;
+    run.events.publish(FetchEvent::MailboxFinished {
+        run_id: run.run_id,
+        account: config.email.clone(),
+        mailbox: mailbox_name.to_string(),
+        messages_fetched: counts.messages_fetched,
+        messages_failed: counts.messages_failed,
+        error: error.map(|(_, message)| message),
+    });
+    result
+}
 
-    // Prepare data for blocking task
-    let config_clone = config.clone();
+/// Records an account or mailbox that failed before it could be synced.
+fn record_failure(
+    db: &Database,
+    run: &RunContext,
+    account_email: &str,
+    mailbox: Option<&str>,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let class = ErrorClass::of(error);
+    let error = format!("{:#}", error);
+    let entry_id = db.start_mailbox_fetch(run.run_id, account_email, mailbox)?;
+    db.finish_mailbox_fetch(
+        entry_id,
+        &FetchCounts::default(),
+        Some((class.as_str(), &error)),
+    )?;
+    if let Some(mailbox) = mailbox {
+        run.events.publish(FetchEvent::MailboxFinished {
+            run_id: run.run_id,
+            account: account_email.to_string(),
+            mailbox: mailbox.to_string(),
+            messages_fetched: 0,
+            messages_failed: 0,
+            error: Some(error),
+        });
+    }
+    Ok(())
+}
+
+/// Syncs one mailbox over the session in `imap`. The session is handed back through `imap`
+/// unless the connection failed, in which case `imap` is left empty so the caller reconnects.
+async fn sync_mailbox(
+    imap: &mut Option<AccountSession>,
+    config: &AccountConfig,
+    mailbox_name: &str,
+    output_dir: &Path,
+    db: &Database,
+    run: &RunContext,
+) -> Result<FetchCounts> {
+    let mut account_session = imap
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("No IMAP session for {}", config.email))?;
+
+    let account_dir = output_dir.join(config.email.replace("@", "_"));
+    let mailbox_dir = account_dir.join(mailbox_name);
+
+    // Select the mailbox first so its UIDVALIDITY can be checked against the database
+    // before deciding which UIDs still need to be fetched
     let mailbox_name_str = mailbox_name.to_string();
-    let output_dir_clone = output_dir.to_path_buf();
-    let email_clone = config.email.clone();
+    let config_clone = config.clone();
+    let (mut account_session, selected) = spawn_blocking_in_span(move || {
+        // A connection lost while selecting is reopened before the next attempt
+        let selected = retry(&config_clone.retry.select, "select", |attempt| {
+            if attempt > 1 {
+                account_session = AccountSession::connect(&config_clone)?;
+            }
+            select_or_examine(&mut account_session, &mailbox_name_str)
+        });
 
-    // Run all IMAP operations in a single blocking task
-    let (saved_count, saved_uids) = tokio::task::spawn_blocking(move || {
-        let mut session = connect_and_login_sync(&config_clone)?;
+        if let Ok(mailbox) = &selected {
+            info!(exists = mailbox.exists, "Selected mailbox");
+        }
+
+        (account_session, selected)
+    })
+    .await?;
 
-        // Select/examine the mailbox
-        println!("Selecting mailbox: {}...", mailbox_name_str);
-        let mailbox = match session.select(mailbox_name_str.as_str()) {
-            Ok(m) => m,
-            Err(_) => {
-                println!("Select failed, trying EXAMINE...");
-                session.examine(mailbox_name_str.as_str())?
+    // A mailbox the server refuses to select leaves the connection usable
+    let mailbox = match selected {
+        Ok(mailbox) => mailbox,
+        Err(e) => {
+            if ErrorClass::of(&e) == ErrorClass::ServerNo {
+                *imap = Some(account_session);
             }
-        };
+            return Err(e);
+        }
+    };
+    let condstore = account_session.condstore;
 
-        println!(
-            "✓ Selected {} ({} messages)",
-            mailbox_name_str, mailbox.exists
-        );
+    // Servers without UIDVALIDITY are tracked as generation 0
+    let uid_validity = mailbox.uid_validity.unwrap_or(0);
+    let known_state = db.get_mailbox_state(&config.email, mailbox_name)?;
+    match known_state.as_ref().map(|state| state.uid_validity) {
+        Some(known) if known != uid_validity => {
+            warn!(
+                old = known,
+                new = uid_validity,
+                "UIDVALIDITY changed, starting a fresh generation"
+            );
+            let archive_dir = archive_uid_validity_generation(&mailbox_dir, known)?;
+            let archived = db.start_uid_validity_generation(
+                &config.email,
+                mailbox_name,
+                known,
+                uid_validity,
+                &archive_dir,
+            )?;
+            info!(
+                archived,
+                path = %archive_dir.display(),
+                "Archived the previous generation"
+            );
+        }
+        Some(_) => {}
+        None => db.set_mailbox_uid_validity(&config.email, mailbox_name, uid_validity)?,
+    }
+
+    // Messages marked as skipped are never fetched again, those that failed before are
+    // retried by every run (and are all a failed-only fetch looks at)
+    let skipped_uids: HashSet<u32> = db
+        .get_failed_uids(&config.email, mailbox_name, uid_validity, true)?
+        .into_iter()
+        .collect();
+    let failed_before = db.get_failed_uids(&config.email, mailbox_name, uid_validity, false)?;
+    let failed_only_criteria = if run.scope.failed_only {
+        if failed_before.is_empty() {
+            info!("No failed messages to retry");
+            *imap = Some(account_session);
+            return Ok(FetchCounts::default());
+        }
+        let uids = format!("UID {}", uid_set(&failed_before));
+        Some(match run.scope.search_criteria() {
+            Some(criteria) => format!("{} {}", uids, criteria),
+            None => uids,
+        })
+    } else {
+        None
+    };
 
-        // Get all UIDs that are NOT DELETED
-        // Using "NOT DELETED" instead of "ALL" to ensure we get all messages
-        // that are actually available (Gmail and other servers may filter "ALL")
-        let uids = session.uid_search("NOT DELETED")?;
-        println!("Found {} messages to fetch (NOT DELETED)", uids.len());
+    // With CONDSTORE and a previous sync of this generation, only messages at or above the
+    // stored UIDNEXT can be new. Otherwise fall back to diffing the full UID list. A targeted
+    // fetch with a date range (or of failed messages only) only searches those.
+    let search = match (
+        failed_only_criteria.or(run.scope.search_criteria()),
+        known_state,
+    ) {
+        (Some(criteria), _) => UidSearch::Criteria(criteria),
+        (
+            None,
+            Some(MailboxState {
+                uid_validity: known,
+                uid_next: Some(uid_next),
+                highest_modseq: Some(highest_modseq),
+            }),
+        ) if condstore && known == uid_validity => {
+            // Failed messages below the stored UIDNEXT (e.g. unskipped ones) are searched too
+            let search_from = failed_before
+                .first()
+                .map_or(uid_next, |uid| (*uid).min(uid_next));
+            if mailbox.highest_modseq == Some(highest_modseq)
+                && mailbox.uid_next == Some(uid_next)
+                && search_from == uid_next
+            {
+                UidSearch::Unchanged
+            } else {
+                UidSearch::Since(search_from, highest_modseq)
+            }
+        }
+        _ => UidSearch::Full,
+    };
+
+    let targeted = matches!(search, UidSearch::Criteria(_));
+    // The failed messages the search is sure to find if they are still on the server, so the
+    // others can be forgotten. A failed-only fetch doesn't search for skipped ones.
+    let searched_failed: Vec<u32> = match search {
+        UidSearch::Full => failed_before.iter().chain(&skipped_uids).copied().collect(),
+        UidSearch::Criteria(_)
+            if run.scope.failed_only && run.scope.search_criteria().is_none() =>
+        {
+            failed_before
+        }
+        _ => Vec::new(),
+    };
+
+    // Get already fetched UIDs of the current generation from the database
+    let fetched_uids = db.get_fetched_uids(&config.email, mailbox_name, uid_validity)?;
+    let fetched_set: HashSet<u32> = fetched_uids.into_iter().collect();
+    let archived_uids = fetched_set.clone();
+    let deleted_uids: HashSet<u32> = db
+        .get_deleted_uids(&config.email, mailbox_name, uid_validity)?
+        .into_iter()
+        .collect();
+    let live_count = fetched_set.len() - deleted_uids.len();
+    let exists = mailbox.exists as usize;
+
+    // Find the UIDs that still need to be archived
+    let label = format!("{}/{}", config.email, mailbox_name);
+    let batch_size = config.fetch_batch_size;
+    let batch_max_bytes = config.fetch_batch_max_bytes;
+    let (
+        mut account_session,
+        mut uids_to_fetch,
+        gmail_metadata,
+        archived_flags,
+        server_uids,
+        gone_failed,
+    ) = spawn_blocking_in_span(move || {
+        let session = &mut account_session.session;
+        let uids: HashSet<u32> = match search {
+            UidSearch::Unchanged => {
+                info!("Mailbox unchanged since last sync (HIGHESTMODSEQ/UIDNEXT match)");
+                HashSet::new()
+            }
+            UidSearch::Since(uid_next, _) => {
+                // "n:*" always matches the highest UID, even if it is below n
+                let uids = session.uid_search(format!("UID {}:* NOT DELETED", uid_next))?;
+                let uids: HashSet<u32> = uids.into_iter().filter(|uid| *uid >= uid_next).collect();
+                info!(
+                    found = uids.len(),
+                    uid_next, "Searched new messages (CONDSTORE)"
+                );
+                uids
+            }
+            UidSearch::Criteria(ref criteria) => {
+                let uids = session.uid_search(format!("NOT DELETED {}", criteria))?;
+                info!(found = uids.len(), criteria = %criteria, "Searched matching messages");
+                uids
+            }
+            UidSearch::Full => {
+                // Get all UIDs that are NOT DELETED
+                // Using "NOT DELETED" instead of "ALL" to ensure we get all messages
+                // that are actually available (Gmail and other servers may filter "ALL")
+                let uids = session.uid_search("NOT DELETED")?;
+                info!(found = uids.len(), "Searched all messages (NOT DELETED)");
+                uids
+            }
+        };
 
-        // Filter out already fetched UIDs
-        let fetched_set_clone = fetched_set.clone();
-        let uids_to_fetch: Vec<u32> = uids
+        // Filter out already fetched and skipped UIDs, oldest first so batches form
+        // contiguous UID ranges
+        let mut uids_to_fetch: Vec<u32> = uids
             .iter()
-            .filter(|uid| !fetched_set_clone.contains(uid))
+            .filter(|uid| !fetched_set.contains(uid) && !skipped_uids.contains(uid))
             .copied()
             .collect();
+        uids_to_fetch.sort_unstable();
 
-        println!(
-            "Already fetched: {}, New to fetch: {}",
-            fetched_set_clone.len(),
-            uids_to_fetch.len()
+        info!(
+            already_fetched = fetched_set.len(),
+            skipped = skipped_uids.len(),
+            to_fetch = uids_to_fetch.len(),
+            "Compared with the archive"
         );
 
-        // Fetch all messages in this blocking task
-        let mut saved_count = 0;
-        let mut failed_count = 0;
-        let mut saved_uids: Vec<(u32, PathBuf, usize)> = Vec::new();
-
-        if !uids_to_fetch.is_empty() {
-            // Create output directory for this account/mailbox
-            let account_dir = output_dir_clone.join(email_clone.replace("@", "_"));
-            let mailbox_dir = account_dir.join(mailbox_name_str.as_str());
-            fs::create_dir_all(&mailbox_dir)?;
-            println!("Saving messages to: {}", mailbox_dir.display());
-
-            for (idx, uid) in uids_to_fetch.iter().enumerate() {
-                print!(
-                    "\rFetching message {}/{} (UID: {})...",
-                    idx + 1,
-                    uids_to_fetch.len(),
-                    uid
+        // Failed messages that are no longer on the server can't be retried
+        let gone_failed: Vec<u32> = searched_failed
+            .into_iter()
+            .filter(|uid| !uids.contains(uid))
+            .collect();
+
+        // Archived messages missing from the server were deleted there. After an incremental
+        // search the full UID list is only needed if the message count doesn't add up.
+        // A date-limited search says nothing about messages outside the range.
+        let expected = live_count + skipped_uids.len() + uids_to_fetch.len();
+        let server_uids = match &search {
+            _ if fetched_set.is_empty() => None,
+            UidSearch::Full => Some(uids),
+            UidSearch::Criteria(_) => None,
+            _ if exists == expected => None,
+            // EXISTS also counts new messages flagged \Deleted, which the search left out
+            UidSearch::Since(from, _)
+                if exists
+                    == expected
+                        + count_new_deleted(session, *from, &fetched_set, &skipped_uids)? =>
+            {
+                None
+            }
+            _ => Some(session.uid_search("NOT DELETED")?),
+        };
+
+        // Flags may have changed on the server since the messages were archived
+        let archived_flags = if fetched_set.is_empty() {
+            HashMap::new()
+        } else {
+            match fetch_archived_flags(session, &search, &fetched_set) {
+                Ok(flags) => flags,
+                Err(e) => {
+                    warn!(error = %format!("{:#}", e), "Failed to re-sync flags");
+                    HashMap::new()
+                }
+            }
+        };
+
+        // On Gmail the same message shows up in every label-mailbox, so identify it by
+        // X-GM-MSGID
+        let mut gmail_metadata = HashMap::new();
+        if let Some(raw) = &account_session.gmail {
+            for chunk in uids_to_fetch.chunks(batch_size.max(1)) {
+                gmail_metadata.extend(gmail::fetch_metadata(raw, &uid_set(chunk))?);
+            }
+        }
+
+        Ok::<_, anyhow::Error>((
+            account_session,
+            uids_to_fetch,
+            gmail_metadata,
+            archived_flags,
+            server_uids,
+            gone_failed,
+        ))
+    })
+    .await??;
+
+    if !gone_failed.is_empty() {
+        let resolved =
+            db.resolve_fetch_failures(&config.email, mailbox_name, uid_validity, &gone_failed)?;
+        info!(resolved, "Forgot failed messages no longer on the server");
+    }
+
+    if let Some(server_uids) = server_uids {
+        let sync = deletions::sync_mailbox_deletions(
+            db,
+            config,
+            output_dir,
+            mailbox_name,
+            uid_validity,
+            &archived_uids,
+            &deleted_uids,
+            &server_uids,
+        )?;
+        if sync.deleted > 0 {
+            info!(
+                deleted = sync.deleted,
+                "Archived messages were deleted on the server"
+            );
+        }
+        if sync.restored > 0 {
+            info!(
+                restored = sync.restored,
+                "Messages reappeared on the server"
+            );
+        }
+        if sync.moved_to_trash > 0 {
+            info!(
+                moved = sync.moved_to_trash,
+                "Moved messages to the trash area"
+            );
+        }
+    }
+
+    let updated =
+        db.update_message_flags(&config.email, mailbox_name, uid_validity, &archived_flags)?;
+    if updated > 0 {
+        info!(updated, "Updated flags of archived messages");
+    }
+
+    // Gmail messages already stored under another label only need a row and their labels
+    if !gmail_metadata.is_empty() {
+        let msgids: Vec<u64> = gmail_metadata.values().map(|m| m.msgid).collect();
+        let stored = db.get_gmail_messages(&config.email, &msgids)?;
+        let mut linked = Vec::new();
+        uids_to_fetch.retain(|uid| {
+            let Some(metadata) = gmail_metadata.get(uid) else {
+                return true;
+            };
+            let Some(message) = stored.get(&metadata.msgid) else {
+                return true;
+            };
+            let fetched = FetchedMessage {
+                uid: *uid,
+                file_path: &message.file_path,
+                size_bytes: message.size_bytes,
+                sha256: message.sha256.as_deref(),
+                attributes: &metadata.attributes,
+                gmail: Some((metadata.msgid, &metadata.labels)),
+            };
+            match db.record_gmail_message(&config.email, mailbox_name, uid_validity, &fetched) {
+                Ok(()) => {
+                    linked.push(*uid);
+                    false
+                }
+                Err(e) => {
+                    error!(uid, error = %format!("{:#}", e), "Failed to record message in database");
+                    true
+                }
+            }
+        });
+        if !linked.is_empty() {
+            info!(
+                linked = linked.len(),
+                "Linked messages already stored under another Gmail label"
+            );
+            db.resolve_fetch_failures(&config.email, mailbox_name, uid_validity, &linked)?;
+        }
+    }
+
+    // Gmail messages go to the account-wide store named by X-GM-MSGID, everything else to
+    // the mailbox directory named by UID
+    let gmail_dir = account_dir.join(GMAIL_STORE_DIR);
+    let targets: HashMap<u32, PathBuf> = uids_to_fetch
+        .iter()
+        .map(|uid| {
+            let path = match gmail_metadata.get(uid) {
+                Some(metadata) => gmail_dir.join(format!("{}.eml", metadata.msgid)),
+                None => mailbox_dir.join(format!("{}.eml", uid)),
+            };
+            (*uid, path)
+        })
+        .collect();
+
+    // With the blob store enabled, messages are stored by content hash instead
+    let blobs = config.blob_store.then(|| BlobStore::new(output_dir));
+
+    run.events.publish(FetchEvent::MailboxSelected {
+        run_id: run.run_id,
+        account: config.email.clone(),
+        mailbox: mailbox_name.to_string(),
+        exists: mailbox.exists,
+        to_fetch: uids_to_fetch.len(),
+    });
+    let mut progress = MailboxProgress::new(
+        &run.events,
+        run.run_id,
+        &config.email,
+        mailbox_name,
+        uids_to_fetch.len(),
+    );
+
+    // Download the remaining messages in a single blocking task, recording them in the
+    // database as they land
+    let control = run.control.clone();
+    let config_clone = config.clone();
+    let mailbox_name_str = mailbox_name.to_string();
+    let db_clone = db.clone();
+    let (account_session, recorded, bytes_recorded, failed, not_fetched, connection_lost) =
+        spawn_blocking_in_span(move || {
+            let mut download = DownloadSession {
+                account_session: &mut account_session,
+                config: &config_clone,
+                mailbox: &mailbox_name_str,
+                uid_validity,
+                lost: false,
+            };
+            let mut writer = MessageWriter {
+                db: &db_clone,
+                account_email: &config_clone.email,
+                mailbox: &mailbox_name_str,
+                uid_validity,
+                targets: &targets,
+                blobs: blobs.as_ref(),
+                gmail_metadata: &gmail_metadata,
+                batch_size,
+                pending: Vec::new(),
+                recorded: Vec::new(),
+                bytes_recorded: 0,
+                failed: Vec::new(),
+            };
+            let mut not_fetched: Vec<u32> = Vec::new();
+
+            if !uids_to_fetch.is_empty() {
+                // Create the output directories for this account/mailbox
+                if blobs.is_none() {
+                    let dirs: HashSet<&Path> =
+                        targets.values().filter_map(|path| path.parent()).collect();
+                    for dir in dirs {
+                        fs::create_dir_all(dir)?;
+                        debug!(path = %dir.display(), "Saving messages");
+                    }
+                }
+
+                not_fetched = fetch_messages_batched(
+                    &mut download,
+                    &mut writer,
+                    &label,
+                    &uids_to_fetch,
+                    batch_size,
+                    batch_max_bytes,
+                    &mut progress,
+                    &control,
                 );
-                std::io::stdout().flush().unwrap();
-
-                match fetch_message_body(&mut session, *uid, true) {
-                    Ok(body) => {
-                        // Save as .eml file
-                        let filename = format!("{}.eml", uid);
-                        let filepath = mailbox_dir.join(&filename);
-                        let size_bytes = body.len();
-
-                        match fs::write(&filepath, &body) {
-                            Ok(_) => {
-                                saved_count += 1;
-                                saved_uids.push((*uid, filepath, size_bytes));
-                            }
-                            Err(e) => {
-                                eprintln!("\n✗ Failed to save {}: {:?}", filepath.display(), e);
-                                failed_count += 1;
+
+                end_progress_line();
+                if not_fetched.is_empty() {
+                    info!(
+                        saved = writer.recorded.len(),
+                        failed = writer.failed.len(),
+                        "Mailbox completed"
+                    );
+                } else {
+                    warn!(
+                        saved = writer.recorded.len(),
+                        failed = writer.failed.len(),
+                        not_fetched = not_fetched.len(),
+                        "Mailbox cancelled"
+                    );
+                }
+            } else {
+                info!("No new messages to fetch");
+            }
+
+            let connection_lost = download.lost;
+            Ok::<_, anyhow::Error>((
+                account_session,
+                writer.recorded,
+                writer.bytes_recorded,
+                writer.failed,
+                not_fetched,
+                connection_lost,
+            ))
+        })
+        .await??;
+    // The caller reconnects for the next mailbox if the connection was lost for good
+    if !connection_lost {
+        *imap = Some(account_session);
+    }
+    let counts = FetchCounts {
+        messages_fetched: recorded.len(),
+        messages_failed: failed.len(),
+        bytes_fetched: bytes_recorded,
+        messages_cancelled: not_fetched.len(),
+    };
+
+    // Keep the failures for the dashboard and the next failed-only fetch, and forget those
+    // of messages that made it this time
+    db.resolve_fetch_failures(&config.email, mailbox_name, uid_validity, &recorded)?;
+    if !failed.is_empty() {
+        let failures: Vec<(u32, &str, &str)> = failed
+            .iter()
+            .map(|failure| (failure.uid, failure.class.as_str(), failure.error.as_str()))
+            .collect();
+        db.record_fetch_failures(&config.email, mailbox_name, uid_validity, &failures)?;
+    }
+
+    // Only advance past UIDs that actually made it into the archive, so failed messages and
+    // those skipped by a cancellation are picked up again by the next incremental run
+    let synced_uid_next = match failed
+        .iter()
+        .map(|failure| failure.uid)
+        .chain(not_fetched.iter().copied())
+        .min()
+    {
+        Some(min_failed) => Some(min_failed),
+        None => mailbox.uid_next,
+    };
+    if !targeted {
+        db.update_mailbox_sync_state(
+            &config.email,
+            mailbox_name,
+            synced_uid_next,
+            mailbox.highest_modseq,
+        )?;
+    }
+
+    Ok(counts)
+}
+
+/// A message that could not be downloaded or saved, and why.
+struct FailedMessage {
+    uid: u32,
+    class: ErrorClass,
+    error: String,
+}
+
+impl FailedMessage {
+    fn new(uid: u32, error: &anyhow::Error) -> Self {
+        FailedMessage {
+            uid,
+            class: ErrorClass::of(error),
+            error: format!("{:#}", error),
+        }
+    }
+}
+
+/// Writes downloaded messages to the archive and records them in the database, in one
+/// transaction per `batch_size` messages while the download is still running. A crash loses
+/// at most the rows of the last batch, whose files are downloaded again by the next run.
+struct MessageWriter<'a> {
+    db: &'a Database,
+    account_email: &'a str,
+    mailbox: &'a str,
+    uid_validity: u32,
+    /// Path of each UID's file (unless the blob store is used)
+    targets: &'a HashMap<u32, PathBuf>,
+    blobs: Option<&'a BlobStore>,
+    gmail_metadata: &'a HashMap<u32, GmailMetadata>,
+    batch_size: usize,
+    /// Written to the archive, not yet recorded in the database
+    pending: Vec<SavedMessage>,
+    recorded: Vec<u32>,
+    bytes_recorded: u64,
+    failed: Vec<FailedMessage>,
+}
+
+impl MessageWriter<'_> {
+    /// Writes a message to the archive. Returns its size.
+    fn save(&mut self, uid: u32, body: &[u8], attributes: MessageAttributes) -> Result<usize> {
+        let saved = save_message(uid, &self.targets[&uid], self.blobs, body, attributes)?;
+        let size_bytes = saved.size_bytes;
+        self.pending.push(saved);
+        if self.pending.len() >= self.batch_size.max(1) {
+            self.flush();
+        }
+        Ok(size_bytes)
+    }
+
+    fn fail(&mut self, uid: u32, error: &anyhow::Error) {
+        self.failed.push(FailedMessage::new(uid, error));
+    }
+
+    /// Records the pending messages in one transaction. If that fails, they count as failed
+    /// and their files are overwritten by the next attempt.
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let messages: Vec<FetchedMessage> = pending
+            .iter()
+            .map(|saved| FetchedMessage {
+                uid: saved.uid,
+                file_path: &saved.file_path,
+                size_bytes: saved.size_bytes,
+                sha256: Some(&saved.sha256),
+                attributes: &saved.attributes,
+                gmail: self
+                    .gmail_metadata
+                    .get(&saved.uid)
+                    .map(|metadata| (metadata.msgid, metadata.labels.as_slice())),
+            })
+            .collect();
+        match self.db.record_fetched_messages(
+            self.account_email,
+            self.mailbox,
+            self.uid_validity,
+            &messages,
+        ) {
+            Ok(()) => {
+                self.recorded.extend(pending.iter().map(|saved| saved.uid));
+                self.bytes_recorded += pending
+                    .iter()
+                    .map(|saved| saved.size_bytes as u64)
+                    .sum::<u64>();
+            }
+            Err(e) => {
+                end_progress_line();
+                error!(
+                    messages = pending.len(),
+                    error = %format!("{:#}", e),
+                    "Failed to record messages in database"
+                );
+                for saved in &pending {
+                    self.fail(saved.uid, &e);
+                }
+            }
+        }
+    }
+}
+
+/// Downloads `uids` with one `UID FETCH` per batch instead of one round-trip per message.
+/// Each body is handed to `writer` as soon as its batch response has been read.
+/// UIDs a batch did not deliver (or whole batches the server rejects) fall back to
+/// `fetch_message_body`.
+/// After a network error the download resumes over a new connection, as the fetch retry
+/// policy allows. If the connection can't be reopened, the download stops and the UIDs that
+/// were not saved are failed with the error the connection was lost with.
+/// Pausing `control` blocks before the next message is saved; cancelling it stops the
+/// download, and the UIDs that were not saved are returned.
+#[allow(clippy::too_many_arguments)]
+fn fetch_messages_batched(
+    download: &mut DownloadSession,
+    writer: &mut MessageWriter,
+    label: &str,
+    uids: &[u32],
+    batch_size: usize,
+    batch_max_bytes: u64,
+    progress: &mut MailboxProgress,
+    control: &FetchControl,
+) -> Vec<u32> {
+    let mut done = 0;
+
+    // Returns false once the fetch has been cancelled, without saving the message
+    let mut record = |uid: u32, result: Result<(Vec<u8>, MessageAttributes)>| {
+        if !control.proceed() {
+            return false;
+        }
+        done += 1;
+        if crate::logging::is_interactive() {
+            print!(
+                "\r[{}] Fetching message {}/{} (UID: {})...",
+                label,
+                done,
+                uids.len(),
+                uid
+            );
+            std::io::stdout().flush().unwrap();
+        }
+
+        match result.and_then(|(body, attributes)| writer.save(uid, &body, attributes)) {
+            Ok(size_bytes) => {
+                debug!(
+                    uid,
+                    bytes = size_bytes,
+                    done,
+                    total = uids.len(),
+                    "Saved message"
+                );
+                progress.saved(uid, size_bytes);
+            }
+            Err(e) => {
+                end_progress_line();
+                error!(uid, error = %format!("{:#}", e), "Failed to fetch message");
+                progress.failed(uid, &e);
+                writer.fail(uid, &e);
+            }
+        }
+        true
+    };
+
+    let mut lost_with = None;
+    'batches: for chunk in uids.chunks(batch_size.max(1)) {
+        let batches = split_by_byte_budget(
+            &mut download.account_session.session,
+            chunk,
+            batch_max_bytes,
+        );
+        for batch in batches {
+            if !control.proceed() {
+                break 'batches;
+            }
+            let mut pending: HashSet<u32> = batch.iter().copied().collect();
+
+            let fetched = download.fetch(|session| {
+                Ok(session.uid_fetch(uid_set(&batch), "(UID FLAGS INTERNALDATE BODY.PEEK[])")?)
+            });
+            match fetched {
+                Ok(msgs) => {
+                    for msg in msgs.iter() {
+                        if let (Some(uid), Some(body)) = (msg.uid, msg.body()) {
+                            if pending.remove(&uid)
+                                && !record(uid, Ok((body.to_vec(), message_attributes(msg))))
+                            {
+                                break 'batches;
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("\n✗ Failed to fetch UID {}: {:?}", uid, e);
-                        failed_count += 1;
-                    }
+                }
+                Err(e) if download.lost => {
+                    lost_with = Some(e);
+                    break 'batches;
+                }
+                Err(e) => {
+                    end_progress_line();
+                    warn!(
+                        uids = batch.len(),
+                        error = %format!("{:#}", e),
+                        "Batch fetch failed, falling back to per-message fetch"
+                    );
                 }
             }
 
-            println!(
-                "\n✓ Completed: {} saved, {} failed",
-                saved_count, failed_count
-            );
+            let mut remaining: Vec<u32> = pending.into_iter().collect();
+            remaining.sort_unstable();
+            for uid in remaining {
+                if !control.proceed() {
+                    break 'batches;
+                }
+                let result = download.fetch(|session| fetch_message_body(session, uid, true));
+                if download.lost {
+                    lost_with = result.err();
+                    break 'batches;
+                }
+                if !record(uid, result) {
+                    break 'batches;
+                }
+            }
+        }
+    }
+
+    writer.flush();
+    let attempted: HashSet<u32> = writer
+        .recorded
+        .iter()
+        .copied()
+        .chain(writer.failed.iter().map(|failure| failure.uid))
+        .collect();
+    let not_fetched: Vec<u32> = uids
+        .iter()
+        .filter(|uid| !attempted.contains(uid))
+        .copied()
+        .collect();
+
+    if let Some(e) = lost_with {
+        end_progress_line();
+        error!(
+            remaining = not_fetched.len(),
+            class = ErrorClass::of(&e).as_str(),
+            error = %format!("{:#}", e),
+            "Connection lost, the remaining messages are left for the next run"
+        );
+        for uid in &not_fetched {
+            writer.fail(*uid, &e);
+        }
+        return Vec::new();
+    }
+
+    not_fetched
+}
+
+/// The connection a mailbox is downloaded over. It is reopened after a network error, so the
+/// download can resume where it stopped.
+struct DownloadSession<'a> {
+    account_session: &'a mut AccountSession,
+    config: &'a AccountConfig,
+    mailbox: &'a str,
+    uid_validity: u32,
+    /// Set once a network error broke the connection, until it has been reopened
+    lost: bool,
+}
+
+impl DownloadSession<'_> {
+    /// Runs `f` on the session, retrying it over a new connection after network errors as
+    /// the account's fetch retry policy allows. `lost` is left set if that didn't help.
+    fn fetch<T>(&mut self, mut f: impl FnMut(&mut ImapSession) -> Result<T>) -> Result<T> {
+        let policy = self.config.retry.fetch;
+        retry(&policy, "fetch", |_| {
+            if self.lost {
+                self.reopen()?;
+            }
+            let result = f(&mut self.account_session.session);
+            if let Err(e) = &result {
+                self.lost = ErrorClass::of(e).is_transient();
+            }
+            result
+        })
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        info!("Reconnecting to resume the download");
+        let mut account_session = AccountSession::connect(self.config)?;
+        let status = select_or_examine(&mut account_session, self.mailbox)?;
+        // Messages of another generation would be saved under the wrong UIDs
+        if status.uid_validity.unwrap_or(0) != self.uid_validity {
+            return Err(anyhow::anyhow!(
+                "UIDVALIDITY of {} changed during the download",
+                self.mailbox
+            ));
+        }
+        *self.account_session = account_session;
+        self.lost = false;
+        Ok(())
+    }
+}
+
+/// Moves off the `\r` progress line so a log line printed in the middle of a download
+/// doesn't get appended to it.
+fn end_progress_line() {
+    if crate::logging::is_interactive() {
+        println!();
+    }
+}
+
+/// Splits `uids` into batches whose combined RFC822.SIZE stays within `max_bytes`. A single
+/// message larger than the budget gets a batch of its own. A budget of 0 disables the split.
+fn split_by_byte_budget(session: &mut ImapSession, uids: &[u32], max_bytes: u64) -> Vec<Vec<u32>> {
+    if max_bytes == 0 {
+        return vec![uids.to_vec()];
+    }
+
+    // If the sizes can't be fetched, treat them as unknown and keep a single batch
+    let sizes: HashMap<u32, u64> = match session.uid_fetch(uid_set(uids), "(UID RFC822.SIZE)") {
+        Ok(msgs) => msgs
+            .iter()
+            .filter_map(|msg| Some((msg.uid?, u64::from(msg.size?))))
+            .collect(),
+        Err(_) => HashMap::new(),
+    };
+    batches_within_budget(uids, &sizes, max_bytes)
+}
+
+/// Groups `uids` in order into batches whose sizes add up to at most `max_bytes`. Messages of
+/// unknown size count as 0.
+fn batches_within_budget(uids: &[u32], sizes: &HashMap<u32, u64>, max_bytes: u64) -> Vec<Vec<u32>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for uid in uids {
+        let size = sizes.get(uid).copied().unwrap_or(0);
+        if !batch.is_empty() && batch_bytes + size > max_bytes {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+        batch.push(*uid);
+        batch_bytes += size;
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+/// Counts the messages from UID `from` on that are flagged \Deleted and neither archived nor
+/// skipped, i.e. those a `NOT DELETED` search for new messages leaves out.
+fn count_new_deleted(
+    session: &mut ImapSession,
+    from: u32,
+    fetched: &HashSet<u32>,
+    skipped: &HashSet<u32>,
+) -> Result<usize> {
+    let uids = session.uid_search(format!("UID {}:* DELETED", from))?;
+    Ok(uids
+        .into_iter()
+        .filter(|uid| *uid >= from && !fetched.contains(uid) && !skipped.contains(uid))
+        .count())
+}
+
+/// Fetches the current flags of the archived messages in `fetched`, asking only for their
+/// UIDs so messages that were never archived aren't sent. After a CONDSTORE sync only
+/// messages changed since its HIGHESTMODSEQ are asked for.
+fn fetch_archived_flags(
+    session: &mut ImapSession,
+    search: &UidSearch,
+    fetched: &HashSet<u32>,
+) -> Result<HashMap<u32, String>> {
+    let query = match search {
+        // Left to the next regular run, a targeted fetch shouldn't sweep the whole mailbox
+        UidSearch::Unchanged | UidSearch::Criteria(_) => return Ok(HashMap::new()),
+        UidSearch::Since(_, highest_modseq) => {
+            format!("(UID FLAGS) (CHANGEDSINCE {})", highest_modseq)
+        }
+        UidSearch::Full => "(UID FLAGS)".to_string(),
+    };
+
+    let mut uids: Vec<u32> = fetched.iter().copied().collect();
+    uids.sort_unstable();
+    let mut flags = HashMap::new();
+    // Bounds the length of the command when the archived UIDs are scattered
+    for chunk in uids.chunks(FLAGS_FETCH_CHUNK) {
+        let msgs = session.uid_fetch(uid_set(chunk), &query)?;
+        flags.extend(
+            msgs.iter()
+                .filter_map(|msg| msg.uid.map(|uid| (uid, msg)))
+                .filter(|(uid, _)| fetched.contains(uid))
+                .map(|(uid, msg)| (uid, database::format_flags(msg.flags()))),
+        );
+    }
+    Ok(flags)
+}
+
+/// Formats sorted UIDs as a compact UID set, e.g. `1:3,7,9:10`.
+fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut iter = uids.iter().copied().peekable();
+    while let Some(start) = iter.next() {
+        let mut end = start;
+        while iter.peek() == Some(&(end + 1)) {
+            end = iter.next().unwrap();
+        }
+        if start == end {
+            ranges.push(start.to_string());
         } else {
-            println!("No new messages to fetch");
+            ranges.push(format!("{}:{}", start, end));
         }
+    }
+    ranges.join(",")
+}
 
-        // Logout (ignore errors)
-        let _ = session.logout();
+/// A message written to the archive, not yet recorded in the database.
+struct SavedMessage {
+    uid: u32,
+    file_path: PathBuf,
+    size_bytes: usize,
+    sha256: String,
+    attributes: MessageAttributes,
+}
+
+/// Writes a message to its file, or to the blob store if given, durably and without ever
+/// leaving a partial file behind. The file carries the date the message arrived on the
+/// server as its modification time.
+fn save_message(
+    uid: u32,
+    filepath: &Path,
+    blobs: Option<&BlobStore>,
+    body: &[u8],
+    attributes: MessageAttributes,
+) -> Result<SavedMessage> {
+    let modified = attributes.internal_date.map(SystemTime::from);
+    let (file_path, sha256) = match blobs {
+        Some(blobs) => {
+            let blob = blobs.store(body, modified)?;
+            (blob.path, blob.sha256)
+        }
+        None => {
+            // Save as .eml file
+            atomic_file::write(filepath, body, modified)
+                .map_err(|e| anyhow::anyhow!("Failed to save {}: {:?}", filepath.display(), e))?;
+            (filepath.to_path_buf(), blob_store::sha256_hex(body))
+        }
+    };
 
-        Ok::<(usize, Vec<(u32, PathBuf, usize)>), anyhow::Error>((saved_count, saved_uids))
+    Ok(SavedMessage {
+        uid,
+        file_path,
+        size_bytes: body.len(),
+        sha256,
+        attributes,
     })
-    .await??;
+}
+
+/// How the UIDs of a mailbox are discovered on this run.
+enum UidSearch {
+    /// Nothing was added or changed since the last run.
+    Unchanged,
+    /// Only UIDs at or above the given UIDNEXT of the last run need to be looked at, and only
+    /// messages changed since the given HIGHESTMODSEQ can have new flags.
+    Since(u32, u64),
+    /// Diff the complete UID list of the mailbox against the database.
+    Full,
+    /// Only messages matching these SEARCH criteria (a targeted fetch's date range, or the
+    /// UIDs of failed messages). The mailbox's sync state is left as it is, since messages
+    /// outside the criteria are skipped.
+    Criteria(String),
+}
+
+/// The parts of a SELECT/EXAMINE response the fetcher cares about.
+#[derive(Debug, PartialEq, Eq)]
+struct MailboxStatus {
+    exists: u32,
+    uid_validity: Option<u32>,
+    uid_next: Option<u32>,
+    highest_modseq: Option<u64>,
+}
+
+/// Selects a mailbox, or examines it if the server refuses to select it (e.g. a read-only
+/// mailbox).
+fn select_or_examine(
+    account_session: &mut AccountSession,
+    mailbox_name: &str,
+) -> Result<MailboxStatus> {
+    let condstore = account_session.condstore;
+    let session = &mut account_session.session;
+    debug!("Selecting mailbox");
+    match select_mailbox(session, mailbox_name, "SELECT", condstore) {
+        Err(e) if ErrorClass::of(&e) == ErrorClass::ServerNo => {
+            debug!(error = %format!("{:#}", e), "SELECT failed, trying EXAMINE");
+            select_mailbox(session, mailbox_name, "EXAMINE", condstore)
+        }
+        result => result,
+    }
+}
+
+/// Selects (or examines) a mailbox. With CONDSTORE the command is sent raw, because the
+/// `imap` crate drops the HIGHESTMODSEQ response code from its `Mailbox` type.
+fn select_mailbox(
+    session: &mut ImapSession,
+    mailbox_name: &str,
+    command: &str,
+    condstore: bool,
+) -> Result<MailboxStatus> {
+    if !condstore {
+        let mailbox = if command == "EXAMINE" {
+            session.examine(mailbox_name)?
+        } else {
+            session.select(mailbox_name)?
+        };
+        return Ok(MailboxStatus {
+            exists: mailbox.exists,
+            uid_validity: mailbox.uid_validity,
+            uid_next: mailbox.uid_next,
+            highest_modseq: None,
+        });
+    }
 
-    // Update database with fetched emails (do this after blocking task)
-    for (uid, filepath, size_bytes) in saved_uids {
-        if let Err(e) =
-            db.mark_email_fetched(&config.email, mailbox_name, uid, &filepath, size_bytes)
+    let response = session.run_command_and_read_response(format!(
+        "{} {} (CONDSTORE)",
+        command,
+        quote_mailbox_name(mailbox_name)
+    ))?;
+    Ok(parse_select_response(&response))
+}
+
+/// Reads the EXISTS count and the UIDVALIDITY, UIDNEXT and HIGHESTMODSEQ response codes from
+/// the untagged lines of a raw SELECT/EXAMINE response. A mailbox without mod-sequences
+/// (`[NOMODSEQ]`) has no HIGHESTMODSEQ.
+fn parse_select_response(response: &[u8]) -> MailboxStatus {
+    let mut status = MailboxStatus {
+        exists: 0,
+        uid_validity: None,
+        uid_next: None,
+        highest_modseq: None,
+    };
+    for line in String::from_utf8_lossy(response).lines() {
+        if let Some(count) = line
+            .strip_prefix("* ")
+            .and_then(|rest| rest.strip_suffix(" EXISTS"))
         {
-            eprintln!("✗ Failed to record UID {} in database: {:?}", uid, e);
+            status.exists = count.parse().unwrap_or(0);
+        } else if let Some(value) = response_code_value(line, "UIDVALIDITY") {
+            status.uid_validity = value.parse().ok();
+        } else if let Some(value) = response_code_value(line, "UIDNEXT") {
+            status.uid_next = value.parse().ok();
+        } else if let Some(value) = response_code_value(line, "HIGHESTMODSEQ") {
+            status.highest_modseq = value.parse().ok();
         }
     }
+    status
+}
 
-    Ok(saved_count)
+/// Extracts the argument of a response code such as `* OK [UIDNEXT 4392] Predicted next UID`.
+fn response_code_value<'a>(line: &'a str, code: &str) -> Option<&'a str> {
+    let start = line.find(&format!("[{} ", code))? + code.len() + 2;
+    let end = start + line[start..].find(']')?;
+    Some(&line[start..end])
 }
 
-// Synchronous version for use in blocking tasks
-fn connect_and_login_sync(config: &AccountConfig) -> Result<Session<TlsStream<TcpStream>>> {
-    let tls = native_tls::TlsConnector::builder().build()?;
-    println!("Connecting to {}:{}", config.server, config.port);
+fn quote_mailbox_name(mailbox_name: &str) -> String {
+    format!(
+        "\"{}\"",
+        mailbox_name.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
 
-    let client = imap::connect(
-        (config.server.as_str(), config.port),
-        config.server.as_str(),
-        &tls,
-    )?;
-    println!("Connected to {}", config.server);
-    println!(
-        "Logging in as {} (username: {})",
-        config.email, config.username
+/// Moves the `.eml` files of a superseded UIDVALIDITY generation out of the way, so that the
+/// new generation can reuse the `<uid>.eml` names without overwriting them.
+pub fn archive_uid_validity_generation(
+    mailbox_dir: &Path,
+    old_uid_validity: u32,
+) -> Result<PathBuf> {
+    let archive_dir = mailbox_dir.join(format!("{}{}", UID_VALIDITY_DIR_PREFIX, old_uid_validity));
+    fs::create_dir_all(&archive_dir)?;
+
+    for entry in fs::read_dir(mailbox_dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "eml") {
+            if let Some(file_name) = path.file_name() {
+                fs::rename(&path, archive_dir.join(file_name))?;
+            }
+        }
+    }
+
+    Ok(archive_dir)
+}
+
+// Synchronous version for use in blocking tasks. Also returns the raw channel on the
+// session's connection, for commands the `imap` crate can't parse. Connecting and logging in
+// are retried as configured in the account's `connect` and `login` retry policies.
+pub fn connect_and_login_sync(config: &AccountConfig) -> Result<(ImapSession, RawChannel)> {
+    retry(&config.retry.login, "login", |_| {
+        let (client, raw) = connect_with_retry(config)?;
+        login(client, raw, config)
+    })
+}
+
+fn connect_with_retry(
+    config: &AccountConfig,
+) -> Result<(imap::Client<connection::ImapStream>, RawChannel)> {
+    retry(&config.retry.connect, "connect", |_| {
+        debug!(
+            server = %config.server,
+            port = config.port,
+            security = ?config.security,
+            "Connecting"
+        );
+        let connected = connection::connect(config)?;
+        debug!(server = %config.server, "Connected");
+        Ok(connected)
+    })
+}
+
+fn login(
+    client: imap::Client<connection::ImapStream>,
+    raw: RawChannel,
+    config: &AccountConfig,
+) -> Result<(ImapSession, RawChannel)> {
+    if config.auth == AuthMethod::OAuth2 {
+        return authenticate_oauth2(client, config).map(|session| (session, raw));
+    }
+
+    debug!(username = %config.username, "Logging in");
+
+    let e = match client.login(&config.username, &config.password) {
+        Ok(session) => {
+            info!(username = %config.username, "Logged in");
+            return Ok((session, raw));
+        }
+        Err((e, _)) => login_error(e),
+    };
+    // A connection lost while logging in is retried by the caller
+    if ErrorClass::of(&e) != ErrorClass::Auth {
+        return Err(e);
+    }
+    error!(username = %config.username, error = %e, "Login failed");
+
+    // For Gmail, if login fails and username contains @, try without the domain
+    if config.server == "imap.gmail.com" && config.username.contains('@') {
+        let username_local = config.username.split('@').next().unwrap();
+        warn!(
+            username = %username_local,
+            "Reconnecting and trying with the local username"
+        );
+
+        // Reconnect for retry
+        let (retry_client, retry_raw) = connect_with_retry(config)?;
+
+        match retry_client.login(username_local, &config.password) {
+            Ok(session) => {
+                info!(username = %username_local, "Logged in");
+                Ok((session, retry_raw))
+            }
+            Err((e2, _)) => {
+                let e2 = login_error(e2);
+                error!(username = %username_local, error = %e2, "Login failed");
+                log_gmail_login_help();
+                Err(e2)
+            }
+        }
+    } else {
+        if config.server == "imap.gmail.com" {
+            log_gmail_login_help();
+        }
+        Err(e)
+    }
+}
+
+/// Rejected credentials are permanent, other login failures (e.g. a dropped connection) may
+/// not be.
+fn login_error(error: imap::Error) -> anyhow::Error {
+    match error {
+        imap::Error::No(_) | imap::Error::Bad(_) => {
+            AuthError(format!("Login failed: {}", error)).into()
+        }
+        error => anyhow::Error::from(error).context("Login failed"),
+    }
+}
+
+fn log_gmail_login_help() {
+    warn!(
+        "Gmail troubleshooting: ensure IMAP is enabled in the Gmail settings and log in with \
+         an app password (not your regular password). App passwords are 16 characters (may \
+         include spaces), require 2FA and are generated at https://myaccount.google.com/apppasswords"
     );
+}
+
+fn authenticate_oauth2(
+    client: imap::Client<connection::ImapStream>,
+    config: &AccountConfig,
+) -> Result<ImapSession> {
+    let access_token = oauth::access_token(config)?;
+    let mechanism = config
+        .oauth2
+        .as_ref()
+        .map(|oauth2| oauth2.mechanism)
+        .unwrap_or_default();
+
+    debug!(username = %config.username, mechanism = ?mechanism, "Authenticating with OAuth2");
+    let result = match mechanism {
+        SaslMechanism::XOAuth2 => client.authenticate(
+            "XOAUTH2",
+            &oauth::XOAuth2 {
+                user: config.username.clone(),
+                access_token,
+            },
+        ),
+        SaslMechanism::OAuthBearer => client.authenticate(
+            "OAUTHBEARER",
+            &oauth::OAuthBearer {
+                user: config.username.clone(),
+                host: config.server.clone(),
+                port: config.port,
+                access_token,
+            },
+        ),
+    };
 
-    match client.login(&config.username, &config.password) {
+    match result {
         Ok(session) => {
-            println!("✓ Successfully logged in!");
+            info!(username = %config.username, "Authenticated with OAuth2");
             Ok(session)
         }
-        Err(e) => {
-            // For Gmail, if login fails and username contains @, try without the domain
-            if config.server == "imap.gmail.com" && config.username.contains('@') {
-                let username_local = config.username.split('@').next().unwrap();
-                println!(
-                    "First attempt failed, reconnecting and trying with local username: {}",
-                    username_local
-                );
+        Err((e, _)) => {
+            // The token may have been revoked early, force a refresh on the next attempt
+            oauth::invalidate(config);
+            let e = login_error(e);
+            error!(username = %config.username, error = %e, "OAuth2 authentication failed");
+            Err(e)
+        }
+    }
+}
 
-                // Reconnect for retry
-                let tls_retry = native_tls::TlsConnector::builder().build()?;
-                let retry_client = imap::connect(
-                    (config.server.as_str(), config.port),
-                    config.server.as_str(),
-                    &tls_retry,
-                )?;
-
-                match retry_client.login(username_local, &config.password) {
-                    Ok(session) => {
-                        println!("✓ Successfully logged in with local username!");
-                        Ok(session)
-                    }
-                    Err(e2) => {
-                        eprintln!(
-                            "❌ Login failed for {} with both username formats",
-                            config.email
-                        );
-                        eprintln!("   Error with '{}': {:?}", config.username, e);
-                        eprintln!("   Error with '{}': {:?}", username_local, e2);
-                        eprintln!("\nGmail troubleshooting:");
-                        eprintln!("1. Ensure IMAP is enabled in Gmail settings");
-                        eprintln!("2. Use an App-Specific Password (not your regular password)");
-                        eprintln!("   Generate one at: https://myaccount.google.com/apppasswords");
-                        eprintln!(
-                            "3. If 2FA is disabled, enable it first (required for app passwords)"
-                        );
-                        eprintln!("4. App passwords are 16 characters (may include spaces)");
-                        Err(anyhow::anyhow!("Login failed: {:?}", e2.0))
+/// A mailbox as the server has it now, for `courrier reindex` to match archived files with.
+pub struct ServerMailbox {
+    pub uid_validity: u32,
+    /// RFC822.SIZE, FLAGS and INTERNALDATE of the archived UIDs still on the server
+    pub messages: HashMap<u32, (u32, MessageAttributes)>,
+    /// X-GM-MSGID and labels of every message, on Gmail
+    pub gmail: HashMap<u32, GmailMetadata>,
+}
+
+/// Logs in to an account and looks up the mailboxes in `archived` (with the UIDs archived
+/// from each), and on Gmail every mailbox the account's filters let through. Mailboxes the
+/// server refuses to select are left out. Blocking.
+pub fn describe_server_mailboxes(
+    account: &AccountConfig,
+    archived: &HashMap<String, HashSet<u32>>,
+) -> Result<HashMap<String, ServerMailbox>> {
+    let _permit = connection::acquire_connection_blocking(account);
+    let mut account_session = AccountSession::connect(account)?;
+
+    let mut names: Vec<String> = archived.keys().cloned().collect();
+    if account_session.gmail.is_some() {
+        let filter = MailboxFilter::new(&account.include_mailboxes, &account.exclude_mailboxes)?;
+        let listed = account_session.session.list(Some(""), Some("*"))?;
+        for name in listed.iter() {
+            if !name.attributes().contains(&NameAttribute::NoSelect)
+                && filter.matches(name)
+                && !archived.contains_key(name.name())
+            {
+                names.push(name.name().to_string());
+            }
+        }
+    }
+
+    let mut mailboxes = HashMap::new();
+    for name in names {
+        let status = match select_or_examine(&mut account_session, &name) {
+            Ok(status) => status,
+            Err(e) if ErrorClass::of(&e) == ErrorClass::ServerNo => {
+                warn!(mailbox = %name, error = %format!("{:#}", e), "Mailbox can't be selected");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut mailbox = ServerMailbox {
+            uid_validity: status.uid_validity.unwrap_or(0),
+            messages: HashMap::new(),
+            gmail: HashMap::new(),
+        };
+        // `1:*` is an error in an empty mailbox on some servers
+        if status.exists > 0 {
+            if let Some(uids) = archived.get(&name).filter(|uids| !uids.is_empty()) {
+                let msgs = account_session
+                    .session
+                    .uid_fetch("1:*", "(UID RFC822.SIZE FLAGS INTERNALDATE)")?;
+                for msg in msgs.iter() {
+                    if let (Some(uid), Some(size)) = (msg.uid, msg.size) {
+                        if uids.contains(&uid) {
+                            mailbox
+                                .messages
+                                .insert(uid, (size, message_attributes(msg)));
+                        }
                     }
                 }
-            } else {
-                // For non-Gmail, just report the error
-                eprintln!("❌ Login failed for {}: {:?}", config.email, e);
-                if config.server == "imap.gmail.com" {
-                    eprintln!("\nGmail troubleshooting:");
-                    eprintln!("1. Ensure IMAP is enabled in Gmail settings");
-                    eprintln!("2. Use an App-Specific Password (not your regular password)");
-                    eprintln!("   Generate one at: https://myaccount.google.com/apppasswords");
-                    eprintln!(
-                        "3. If 2FA is disabled, enable it first (required for app passwords)"
-                    );
-                    eprintln!("4. App passwords are 16 characters (may include spaces)");
-                }
-                Err(anyhow::anyhow!("Login failed: {:?}", e.0))
+            }
+            if let Some(raw) = &account_session.gmail {
+                mailbox.gmail = gmail::fetch_metadata(raw, "1:*")?;
             }
         }
+        mailboxes.insert(name, mailbox);
     }
+
+    account_session.logout();
+    Ok(mailboxes)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn fetch_all_accounts(
     accounts: &[AccountConfig],
     output_dir: &Path,
     db: &Database,
+    max_concurrent_accounts: usize,
+    scope: &FetchScope,
+    trigger: FetchTrigger,
+    events: &EventBus,
+    control: FetchControl,
 ) -> Result<usize> {
-    let mut total_saved = 0;
+    let accounts = scope.select_accounts(accounts)?;
+    let run = RunContext::start(
+        db,
+        scope.clone(),
+        trigger,
+        events,
+        control,
+        accounts.iter().map(|a| a.email.clone()).collect(),
+    )?;
+    let run = &run;
 
-    for account in accounts {
-        println!("\n{}", "=".repeat(80));
-        println!("Processing account: {}", account.email);
-        println!("{}", "=".repeat(80));
-
-        // Get all mailboxes from LIST command
-        let account_clone = account.clone();
-        let mailboxes = tokio::task::spawn_blocking(move || {
-            let mut session = connect_and_login_sync(&account_clone)?;
-            println!("Listing all mailboxes...");
-            let mailboxes = session.list(Some(""), Some("*"))?;
-            let _ = session.logout();
-
-            // Extract mailbox names from the LIST response
-            let mailbox_names: Vec<String> = mailboxes
-                .iter()
-                .map(|name| name.name().to_string())
-                .collect();
-
-            Ok::<Vec<String>, anyhow::Error>(mailbox_names)
+    let account_fetches: Vec<_> = accounts
+        .iter()
+        .map(|account| {
+            let span = info_span!(parent: &run.span, "account", account = %account.email);
+            async move {
+                // Covers the account's session and the ones replacing it after a drop
+                let _permit = connection::acquire_connection(account).await;
+                let result = fetch_account(account, output_dir, db, run).await;
+                run.events.publish(FetchEvent::AccountFinished {
+                    run_id: run.run_id,
+                    account: account.email.clone(),
+                    messages_fetched: *result.as_ref().unwrap_or(&0),
+                    error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                });
+                (account.email.as_str(), result)
+            }
+            .instrument(span)
         })
-        .await??;
+        .collect();
 
-        println!("Found {} mailbox(es):", mailboxes.len());
-        for mailbox_name in &mailboxes {
-            println!("  - {}", mailbox_name);
+    let results: Vec<(&str, Result<usize>)> = stream::iter(account_fetches)
+        .buffer_unordered(max_concurrent_accounts.max(1))
+        .collect()
+        .await;
+
+    let _run_span = run.span.enter();
+    let mut total_saved = 0;
+    for (email, result) in results {
+        match result {
+            Ok(count) => {
+                info!(account = %email, saved = count, "Account done");
+                total_saved += count;
+            }
+            Err(e) => {
+                error!(account = %email, error = %format!("{:#}", e), "Account failed");
+                record_failure(db, run, email, None, &e)?;
+            }
         }
+    }
+    if run.control.is_cancelled() {
+        warn!("Fetch cancelled, messages downloaded so far have been kept");
+    }
+    run.finish(db, total_saved)?;
+
+    Ok(total_saved)
+}
+
+async fn fetch_account(
+    account: &AccountConfig,
+    output_dir: &Path,
+    db: &Database,
+    run: &RunContext,
+) -> Result<usize> {
+    let mut total_saved = 0;
+    // Accounts still waiting for their turn when the fetch is cancelled are skipped
+    if !run.control.proceed_async().await {
+        return Ok(0);
+    }
+    run.events.publish(FetchEvent::AccountStarted {
+        run_id: run.run_id,
+        account: account.email.clone(),
+    });
 
-        // Fetch from all mailboxes
-        for mailbox in &mailboxes {
-            println!("\n--- Fetching from mailbox: {} ---", mailbox);
+    info!("Processing account");
 
-            match fetch_all_messages_from_mailbox(account, mailbox, output_dir, db).await {
-                Ok(count) => {
-                    println!(
-                        "✓ Successfully saved {} messages from {}/{}",
-                        count, account.email, mailbox
+    // Log in once and get all mailboxes from LIST command. The same session is then
+    // used to SELECT each mailbox in turn.
+    let filter = MailboxFilter::new(&account.include_mailboxes, &account.exclude_mailboxes)?
+        .require(run.scope.mailbox.as_deref())?;
+    let account_clone = account.clone();
+    let (account_session, mailboxes) = spawn_blocking_in_span(move || {
+        let mut account_session = AccountSession::connect(&account_clone)?;
+        debug!("Listing mailboxes");
+        let mailboxes = account_session.session.list(Some(""), Some("*"))?;
+
+        // Extract mailbox names from the LIST response, skipping names that can't be
+        // selected (e.g. Gmail's "[Gmail]" container) and those filtered out by config
+        let mailbox_names: Vec<String> = mailboxes
+            .iter()
+            .filter(|name| !name.attributes().contains(&NameAttribute::NoSelect))
+            .filter(|name| {
+                let selected = filter.matches(name);
+                if !selected {
+                    debug!(
+                        mailbox = %name.name(),
+                        "Skipping mailbox excluded by filters"
                     );
-                    total_saved += count;
                 }
+                selected
+            })
+            .map(|name| name.name().to_string())
+            .collect();
+        drop(mailboxes);
+
+        Ok::<_, anyhow::Error>((account_session, mailbox_names))
+    })
+    .await??;
+    let mut imap = Some(account_session);
+
+    // A failed-only fetch leaves out the mailboxes without failed messages
+    let mailboxes = if run.scope.failed_only {
+        let failed = db.get_failed_mailboxes(&account.email)?;
+        mailboxes
+            .into_iter()
+            .filter(|mailbox| failed.contains(mailbox))
+            .collect()
+    } else {
+        mailboxes
+    };
+
+    info!(
+        count = mailboxes.len(),
+        mailboxes = %mailboxes.join(", "),
+        "Listed mailboxes"
+    );
+
+    // Fetch from all mailboxes
+    for mailbox in &mailboxes {
+        if !run.control.proceed_async().await {
+            warn!("Fetch cancelled, skipping the remaining mailboxes");
+            break;
+        }
+
+        // Reconnect only if the previous mailbox lost the connection
+        if imap.is_none() {
+            info!(server = %account.server, "Reconnecting");
+            match open_account_session(account).await {
+                Ok(account_session) => imap = Some(account_session),
                 Err(e) => {
-                    eprintln!(
-                        "✗ Failed to fetch from {}/{}: {:?}",
-                        account.email, mailbox, e
-                    );
+                    error!(mailbox = %mailbox, error = %format!("{:#}", e), "Failed to reconnect");
+                    record_failure(db, run, &account.email, Some(mailbox), &e)?;
+                    continue;
                 }
             }
         }
+
+        match sync_mailbox_recorded(&mut imap, account, mailbox, output_dir, db, run).await {
+            Ok(counts) => {
+                info!(
+                    mailbox = %mailbox,
+                    saved = counts.messages_fetched,
+                    "Mailbox done"
+                );
+                total_saved += counts.messages_fetched;
+            }
+            Err(e) => {
+                error!(mailbox = %mailbox, error = %format!("{:#}", e), "Mailbox failed");
+            }
+        }
+    }
+
+    if let Some(account_session) = imap {
+        close_account_session(account_session).await;
+    }
+
+    match deletions::purge_expired(db, account) {
+        Ok(0) => {}
+        Ok(purged) => info!(purged, "Purged messages deleted on the server"),
+        Err(e) => error!(
+            error = %format!("{:#}", e),
+            "Failed to purge deleted messages"
+        ),
     }
 
     Ok(total_saved)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_condstore_select_responses() {
+        let status = parse_select_response(
+            b"* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n\
+              * 172 EXISTS\r\n\
+              * 1 RECENT\r\n\
+              * OK [UIDVALIDITY 3857529045] UIDs valid\r\n\
+              * OK [UIDNEXT 4392] Predicted next UID\r\n\
+              * OK [HIGHESTMODSEQ 715194045007] Highest\r\n",
+        );
+        assert_eq!(
+            status,
+            MailboxStatus {
+                exists: 172,
+                uid_validity: Some(3857529045),
+                uid_next: Some(4392),
+                highest_modseq: Some(715194045007),
+            }
+        );
+
+        // A mailbox that doesn't keep mod-sequences, on a server that leaves out UIDNEXT
+        let status = parse_select_response(
+            b"* 3 EXISTS\r\n\
+              * OK [UIDVALIDITY 7] UIDs valid\r\n\
+              * OK [NOMODSEQ] Sorry, this mailbox format doesn't support modsequences\r\n",
+        );
+        assert_eq!(
+            status,
+            MailboxStatus {
+                exists: 3,
+                uid_validity: Some(7),
+                uid_next: None,
+                highest_modseq: None,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_response_code_values() {
+        let line = "* OK [UIDNEXT 4392] Predicted next UID";
+        assert_eq!(response_code_value(line, "UIDNEXT"), Some("4392"));
+        assert_eq!(response_code_value(line, "UIDVALIDITY"), None);
+        assert_eq!(response_code_value("* OK [NOMODSEQ] x", "NOMODSEQ"), None);
+        assert_eq!(response_code_value("* OK [UIDNEXT 4392", "UIDNEXT"), None);
+    }
+
+    #[test]
+    fn batches_stay_within_the_byte_budget() {
+        let sizes = HashMap::from([(1, 400), (2, 400), (3, 300), (4, 900), (5, 100)]);
+        assert_eq!(
+            batches_within_budget(&[1, 2, 3, 4, 5], &sizes, 1000),
+            vec![vec![1, 2], vec![3], vec![4, 5]]
+        );
+    }
+
+    #[test]
+    fn message_over_the_budget_gets_its_own_batch() {
+        let sizes = HashMap::from([(1, 100), (2, 5000), (3, 100)]);
+        assert_eq!(
+            batches_within_budget(&[1, 2, 3], &sizes, 1000),
+            vec![vec![1], vec![2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn unknown_sizes_count_as_zero() {
+        let sizes = HashMap::from([(2, 800)]);
+        assert_eq!(
+            batches_within_budget(&[1, 2, 3, 4], &sizes, 1000),
+            vec![vec![1, 2, 3, 4]]
+        );
+        assert!(batches_within_budget(&[], &sizes, 1000).is_empty());
+    }
+
+    #[test]
+    fn uid_set_compacts_runs() {
+        assert_eq!(uid_set(&[1, 2, 3, 7, 9, 10]), "1:3,7,9:10");
+        assert_eq!(uid_set(&[5]), "5");
+        assert_eq!(uid_set(&[]), "");
+    }
+}
@@ -0,0 +1,136 @@
+use crate::connection::RawChannel;
+use crate::database::MessageAttributes;
+use anyhow::Result;
+use chrono::DateTime;
+use imap_proto::{AttributeValue, Response};
+use std::collections::HashMap;
+
+/// Capability advertised by Gmail for the X-GM-MSGID/X-GM-LABELS extensions.
+pub const CAPABILITY: &str = "X-GM-EXT-1";
+
+/// `date-time` of RFC 3501, e.g. `17-Jul-1996 02:44:25 -0700`
+const INTERNAL_DATE_FORMAT: &str = "%d-%b-%Y %H:%M:%S %z";
+
+/// Gmail's identity of a message across all label-mailboxes, plus the labels it carries.
+#[derive(Debug, Clone)]
+pub struct GmailMetadata {
+    pub msgid: u64,
+    pub labels: Vec<String>,
+    /// Flags and INTERNALDATE in the selected mailbox, for messages that are only linked
+    pub attributes: MessageAttributes,
+}
+
+/// Fetches X-GM-MSGID and X-GM-LABELS (plus FLAGS and INTERNALDATE) for `uid_set` in the
+/// selected mailbox. The `imap` crate can't parse the X-GM-* FETCH items, so the command goes
+/// through the raw channel.
+pub fn fetch_metadata(raw: &RawChannel, uid_set: &str) -> Result<HashMap<u32, GmailMetadata>> {
+    let mut metadata = HashMap::new();
+
+    raw.run_command(
+        &format!(
+            "UID FETCH {} (UID FLAGS INTERNALDATE X-GM-MSGID X-GM-LABELS)",
+            uid_set
+        ),
+        |response| {
+            let Response::Fetch(_, attributes) = response else {
+                return;
+            };
+
+            let mut uid = None;
+            let mut msgid = None;
+            let mut labels = Vec::new();
+            let mut flags = Vec::new();
+            let mut internal_date = None;
+            for attribute in attributes {
+                match attribute {
+                    AttributeValue::Uid(value) => uid = Some(value),
+                    AttributeValue::Flags(values) => flags = values,
+                    AttributeValue::InternalDate(value) => {
+                        internal_date =
+                            DateTime::parse_from_str(value.trim(), INTERNAL_DATE_FORMAT).ok()
+                    }
+                    AttributeValue::GmailMsgId(value) => msgid = Some(value),
+                    AttributeValue::GmailLabels(values) => {
+                        labels = values.into_iter().map(|label| label.into_owned()).collect()
+                    }
+                    _ => {}
+                }
+            }
+
+            if let (Some(uid), Some(msgid)) = (uid, msgid) {
+                metadata.insert(
+                    uid,
+                    GmailMetadata {
+                        msgid,
+                        labels,
+                        attributes: MessageAttributes::new(flags, internal_date),
+                    },
+                );
+            }
+        },
+    )?;
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Answers the first command on a connection with `chunks`, written one at a time so
+    /// the client sees them in separate reads.
+    fn serve_chunks(chunks: Vec<&'static [u8]>) -> (TcpStream, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = String::new();
+            BufReader::new(&stream).read_line(&mut command).unwrap();
+            for chunk in chunks {
+                stream.write_all(chunk).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+            command
+        });
+        (TcpStream::connect(address).unwrap(), server)
+    }
+
+    #[test]
+    fn fetches_message_ids_and_labels() {
+        let (tcp, server) = serve_chunks(vec![
+            b"* 1 FETCH (UID 42 FLAGS (\\Seen) INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" ",
+            b"X-GM-MSGID 1278455344230334865 X-GM-LABELS (\\Inbox \"Some Label\"))\r",
+            b"\n* 2 FETCH (UID 43 X-GM-MSGID 1278455344230334866 X-GM-LABELS ())\r\n",
+            // Without X-GM-MSGID, e.g. a message expunged meanwhile
+            b"* 3 FETCH (UID 44 FLAGS ())\r\n",
+            b"r1 OK Success\r\n",
+        ]);
+
+        let metadata = fetch_metadata(&RawChannel::for_test(tcp), "42:44").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            "r1 UID FETCH 42:44 (UID FLAGS INTERNALDATE X-GM-MSGID X-GM-LABELS)\r\n"
+        );
+        assert_eq!(metadata.len(), 2);
+        let first = &metadata[&42];
+        assert_eq!(first.msgid, 1278455344230334865);
+        assert_eq!(first.labels, ["\\Inbox", "Some Label"]);
+        assert_eq!(first.attributes.flags, "\\Seen");
+        assert!(first.attributes.internal_date.is_some());
+        assert_eq!(metadata[&43].msgid, 1278455344230334866);
+        assert!(metadata[&43].labels.is_empty());
+    }
+
+    #[test]
+    fn fails_when_the_server_refuses() {
+        let (tcp, server) = serve_chunks(vec![b"r1 BAD Unknown attribute\r\n"]);
+        let error = fetch_metadata(&RawChannel::for_test(tcp), "1:*").unwrap_err();
+        server.join().unwrap();
+        assert!(error.to_string().contains("Unknown attribute"));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::config::AccountConfig;
+use crate::connection;
+use crate::fetcher::connect_and_login_sync;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// A change IDLE reported in a watched mailbox.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IdleWakeup {
+    pub account: String,
+    pub mailbox: String,
+}
+
+/// Starts one IDLE watcher per configured `idle_mailboxes` entry of every account. Watchers
+/// don't fetch themselves, they send a wakeup for each change, so the fetch runs like any
+/// other and can't overlap one.
+pub fn spawn_idle_watchers(
+    accounts: Arc<Vec<AccountConfig>>,
+    keepalive: Duration,
+    wakeups: mpsc::UnboundedSender<IdleWakeup>,
+) {
+    for account in accounts.iter() {
+        for mailbox in &account.idle_mailboxes {
+            let span = info_span!("idle", account = %account.email, mailbox = %mailbox);
+            span.in_scope(|| info!("Starting IDLE watcher"));
+            let account = account.clone();
+            let mailbox = mailbox.clone();
+            let wakeups = wakeups.clone();
+            tokio::task::spawn_blocking(move || {
+                let _entered = span.enter();
+                idle_loop(&account, &mailbox, keepalive, &wakeups);
+            });
+        }
+    }
+}
+
+/// The `mailbox` pattern of a fetch scope matching exactly the given mailbox names.
+pub fn exact_mailbox_pattern<'a>(mailboxes: impl IntoIterator<Item = &'a str>) -> String {
+    let names: Vec<String> = mailboxes.into_iter().map(regex::escape).collect();
+    format!("re:^(?:{})$", names.join("|"))
+}
+
+/// Keeps an IDLE connection open, reconnecting whenever the server drops it. Returns once the
+/// receiving side of `wakeups` is gone, or if the server doesn't support IDLE.
+fn idle_loop(
+    account: &AccountConfig,
+    mailbox: &str,
+    keepalive: Duration,
+    wakeups: &mpsc::UnboundedSender<IdleWakeup>,
+) {
+    loop {
+        match idle_session(account, mailbox, keepalive, wakeups) {
+            Ok(()) => return,
+            Err(e) => {
+                error!(
+                    error = format!("{:#}", e),
+                    retry_in_seconds = RECONNECT_DELAY.as_secs(),
+                    "IDLE connection lost, reconnecting"
+                );
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn idle_session(
+    account: &AccountConfig,
+    mailbox: &str,
+    keepalive: Duration,
+    wakeups: &mpsc::UnboundedSender<IdleWakeup>,
+) -> Result<()> {
+    // Held while watching, so the watcher counts against the server's `max_connections`
+    let _permit = connection::acquire_connection_blocking(account);
+    let (mut session, _) = connect_and_login_sync(account)?;
+
+    let supports_idle = session.capabilities()?.has_str("IDLE");
+    if !supports_idle {
+        // Reconnecting won't change that
+        error!("Server does not advertise IDLE, stopping the watcher");
+        let _ = session.logout();
+        return Ok(());
+    }
+
+    session.examine(mailbox)?;
+    info!("Watching mailbox with IDLE");
+
+    // Catch up on anything that arrived while we were not connected
+    if notify(wakeups, account, mailbox) {
+        return Ok(());
+    }
+
+    loop {
+        // `wait_keepalive` re-issues IDLE every `keepalive` and returns on the first
+        // untagged response (EXISTS, EXPUNGE, FETCH), or errors if the connection drops
+        let mut handle = session.idle()?;
+        handle.set_keepalive(keepalive);
+        handle.wait_keepalive()?;
+
+        info!("Change reported by IDLE");
+        if notify(wakeups, account, mailbox) {
+            return Ok(());
+        }
+    }
+}
+
+/// Queues a fetch of the mailbox. Returns `true` if the receiver has shut down.
+fn notify(
+    wakeups: &mpsc::UnboundedSender<IdleWakeup>,
+    account: &AccountConfig,
+    mailbox: &str,
+) -> bool {
+    wakeups
+        .send(IdleWakeup {
+            account: account.email.clone(),
+            mailbox: mailbox.to_string(),
+        })
+        .is_err()
+}
@@ -0,0 +1,100 @@
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
+use anyhow::{Context, Result};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+/// Overrides `[logging] level`, e.g. `COURRIER_LOG=courrier=debug`.
+const LEVEL_ENV: &str = "COURRIER_LOG";
+/// Overrides `[logging] format` (`plain` or `json`).
+const FORMAT_ENV: &str = "COURRIER_LOG_FORMAT";
+
+static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Installs the global subscriber: stdout, plus the rotating log file if configured. The
+/// returned guard flushes the file on drop and has to be kept alive until exit.
+pub fn init(config: &LoggingConfig) -> Result<Option<WorkerGuard>> {
+    let level = std::env::var(LEVEL_ENV).unwrap_or_else(|_| config.level.clone());
+    let filter =
+        EnvFilter::try_new(&level).with_context(|| format!("Invalid log level: {}", level))?;
+    let format = match std::env::var(FORMAT_ENV).as_deref() {
+        Ok("json") => LogFormat::Json,
+        Ok("plain") => LogFormat::Plain,
+        Ok(other) => {
+            return Err(anyhow::anyhow!(
+                "Invalid {}: {} (expected plain or json)",
+                FORMAT_ENV,
+                other
+            ))
+        }
+        Err(_) => config.format,
+    };
+
+    let terminal = std::io::stdout().is_terminal();
+    INTERACTIVE.store(terminal && format == LogFormat::Plain, Ordering::Relaxed);
+
+    let mut layers: Vec<BoxedLayer> = vec![format_layer(format, std::io::stdout, terminal)];
+    let guard = match &config.file {
+        Some(path) => {
+            let directory = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            let prefix = path
+                .file_stem()
+                .with_context(|| format!("Invalid log file: {}", path.display()))?;
+            let mut builder = RollingFileAppender::builder()
+                .rotation(match config.rotation {
+                    LogRotation::Minutely => Rotation::MINUTELY,
+                    LogRotation::Hourly => Rotation::HOURLY,
+                    LogRotation::Daily => Rotation::DAILY,
+                    LogRotation::Never => Rotation::NEVER,
+                })
+                .filename_prefix(prefix.to_string_lossy());
+            if let Some(extension) = path.extension() {
+                builder = builder.filename_suffix(extension.to_string_lossy());
+            }
+            if let Some(max_files) = config.max_files {
+                builder = builder.max_log_files(max_files.max(1));
+            }
+            let appender = builder
+                .build(directory.unwrap_or_else(|| std::path::Path::new(".")))
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            layers.push(format_layer(format, writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .context("Failed to install the logger")?;
+    Ok(guard)
+}
+
+/// True when stdout is a terminal showing plain logs, the only place a `\r` progress line
+/// makes sense.
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::Relaxed)
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Plain => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
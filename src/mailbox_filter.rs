@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use imap::types::{Name, NameAttribute};
+use regex::Regex;
+
+/// A single `include_mailboxes`/`exclude_mailboxes` entry.
+///
+/// - `\Junk`, `\Trash`, ... match the RFC 6154 special-use attribute of a mailbox
+/// - `re:<regex>` matches the mailbox name against a regular expression
+/// - anything else is a glob on the mailbox name (`*` also matches the hierarchy delimiter)
+enum Pattern {
+    SpecialUse(String),
+    Regex(Regex),
+    Glob(GlobMatcher),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        if pattern.starts_with('\\') {
+            Ok(Pattern::SpecialUse(pattern.to_string()))
+        } else if let Some(regex) = pattern.strip_prefix("re:") {
+            Ok(Pattern::Regex(Regex::new(regex).with_context(|| {
+                format!("Invalid mailbox regex: {}", pattern)
+            })?))
+        } else {
+            Ok(Pattern::Glob(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid mailbox glob: {}", pattern))?
+                    .compile_matcher(),
+            ))
+        }
+    }
+
+    fn matches(&self, name: &Name) -> bool {
+        match self {
+            Pattern::SpecialUse(attribute) => name.attributes().iter().any(|a| match a {
+                NameAttribute::Custom(custom) => custom.eq_ignore_ascii_case(attribute),
+                _ => false,
+            }),
+            Pattern::Regex(regex) => regex.is_match(name.name()),
+            Pattern::Glob(glob) => glob.is_match(name.name()),
+        }
+    }
+}
+
+/// Decides which listed mailboxes of an account get fetched. A mailbox is fetched when it
+/// matches at least one include pattern (or there are none) and no exclude pattern.
+pub struct MailboxFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Mailbox a targeted fetch is limited to, as given and parsed
+    required: Option<(String, Pattern)>,
+}
+
+impl MailboxFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(MailboxFilter {
+            include: include
+                .iter()
+                .map(|p| Pattern::parse(p))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| Pattern::parse(p))
+                .collect::<Result<_>>()?,
+            required: None,
+        })
+    }
+
+    /// Additionally requires mailboxes to match `pattern`, or to be named exactly that (so
+    /// names like `[Gmail]/Sent Mail` don't have to be escaped).
+    pub fn require(mut self, pattern: Option<&str>) -> Result<Self> {
+        if let Some(pattern) = pattern {
+            self.required = Some((pattern.to_string(), Pattern::parse(pattern)?));
+        }
+        Ok(self)
+    }
+
+    pub fn matches(&self, name: &Name) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+            && self
+                .required
+                .as_ref()
+                .is_none_or(|(raw, p)| raw == name.name() || p.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap::types::ZeroCopy;
+    use std::io::{self, Cursor, Read, Write};
+
+    /// Plays back canned server responses and ignores the commands sent.
+    struct Replay(Cursor<Vec<u8>>);
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The mailboxes of a LIST response with the given `(attributes) "/" name` lines.
+    fn list(lines: &[&str]) -> ZeroCopy<Vec<Name>> {
+        let mut responses = b"a1 OK logged in\r\n".to_vec();
+        for line in lines {
+            responses.extend(format!("* LIST {}\r\n", line).as_bytes());
+        }
+        responses.extend(b"a2 OK done\r\n");
+        let client = imap::Client::new(Replay(Cursor::new(responses)));
+        let mut session = client
+            .login("user", "password")
+            .map_err(|(e, _)| e)
+            .unwrap();
+        session.list(None, Some("*")).unwrap()
+    }
+
+    fn matching(filter: &MailboxFilter, names: &[Name]) -> Vec<String> {
+        names
+            .iter()
+            .filter(|name| filter.matches(name))
+            .map(|name| name.name().to_string())
+            .collect()
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn globs_span_the_hierarchy() {
+        let names = list(&[
+            r#"() "/" INBOX"#,
+            r#"() "/" Archive"#,
+            r#"() "/" Archive/2024"#,
+            r#"() "/" Archive/2024/Q1"#,
+        ]);
+        let filter = MailboxFilter::new(&[], &patterns(&["Archive/*"])).unwrap();
+        assert_eq!(matching(&filter, &names), ["INBOX", "Archive"]);
+
+        let filter = MailboxFilter::new(&patterns(&["Arch*"]), &[]).unwrap();
+        assert_eq!(
+            matching(&filter, &names),
+            ["Archive", "Archive/2024", "Archive/2024/Q1"]
+        );
+    }
+
+    #[test]
+    fn special_use_matches_attributes() {
+        let names = list(&[
+            r#"(\HasNoChildren) "/" INBOX"#,
+            r#"(\HasNoChildren \Junk) "/" Spam"#,
+            r#"(\HasNoChildren \Trash) "/" "Deleted Items""#,
+        ]);
+        let filter = MailboxFilter::new(&[], &patterns(&["\\Junk", "\\trash"])).unwrap();
+        assert_eq!(matching(&filter, &names), ["INBOX"]);
+    }
+
+    #[test]
+    fn regex_and_exclude_win_over_include() {
+        let names = list(&[r#"() "/" INBOX"#, r#"() "/" INBOX/Lists"#, r#"() "/" Sent"#]);
+        let filter =
+            MailboxFilter::new(&patterns(&["re:^INBOX"]), &patterns(&["*/Lists"])).unwrap();
+        assert_eq!(matching(&filter, &names), ["INBOX"]);
+    }
+
+    #[test]
+    fn required_mailbox_matches_its_exact_name() {
+        let names = list(&[
+            r#"() "/" INBOX"#,
+            r#"() "/" "[Gmail]/Sent Mail""#,
+            r#"() "/" S"#,
+        ]);
+        let filter = MailboxFilter::new(&[], &[])
+            .unwrap()
+            .require(Some("[Gmail]/Sent Mail"))
+            .unwrap();
+        assert_eq!(matching(&filter, &names), ["[Gmail]/Sent Mail"]);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(MailboxFilter::new(&patterns(&["re:("]), &[]).is_err());
+        assert!(MailboxFilter::new(&[], &patterns(&["a[b"])).is_err());
+    }
+}
@@ -1,36 +1,63 @@
+mod atomic_file;
+mod blob_store;
 mod config;
+mod connection;
+mod control;
 mod database;
+mod deletions;
+mod events;
+mod fetch_scope;
 mod fetcher;
+mod gmail;
+mod idle;
+mod logging;
+mod mailbox_filter;
+mod metrics;
+mod oauth;
+mod reindex;
+mod retry;
 mod server;
+mod verify;
 
 use anyhow::Result;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     let command = args.get(1).map(|s| s.as_str());
 
+    // Load configuration, then set up logging as configured there
+    let app_config = config::load_config()?;
+    let _log_guard = logging::init(&app_config.logging)?;
+    let accounts = config::extract_accounts(&app_config);
+    info!(accounts = accounts.len(), "Loaded Config.toml");
+
     // Initialize database - use environment variable if set, otherwise default to "courrier.db"
     let db_path = std::env::var("COURRIER_DB_PATH").unwrap_or_else(|_| "courrier.db".to_string());
     let db = database::Database::new(&db_path)?;
 
-    // Load configuration
-    let app_config = config::load_config()?;
-    let accounts = config::extract_accounts(&app_config);
-    println!("Loaded {} account(s) from Config.toml", accounts.len());
-
     // Create output directory from config
     let output_dir = PathBuf::from(&app_config.email_storage_path);
     std::fs::create_dir_all(&output_dir)?;
-    println!("Output directory: {}", output_dir.display());
+    info!(path = %output_dir.display(), "Output directory");
 
     match command {
         Some("fetch") => {
-            // CLI mode: one-time fetch
-            run_fetch(&accounts, &output_dir, &db).await?;
+            // CLI mode: one-time fetch, optionally limited by --account/--mailbox/--since/--before
+            // or to the messages that failed before with --failed-only
+            let scope = fetch_scope::FetchScope::from_args(&args[2..])?;
+            run_fetch(
+                &accounts,
+                &output_dir,
+                &db,
+                app_config.max_concurrent_accounts,
+                &scope,
+            )
+            .await?;
         }
         Some("server") | None => {
             // Server mode: start dashboard
@@ -41,16 +68,49 @@ async fn main() -> Result<()> {
                 config: Arc::new(accounts),
                 output_dir: Arc::new(output_dir),
                 fetch_task: Arc::new(Mutex::new(None)),
+                idle_fetch_task: Arc::new(Mutex::new(None)),
+                verify_job: Arc::new(Mutex::new(None)),
+                events: events::EventBus::new(),
                 fetch_interval_seconds: app_config.fetch_interval_seconds,
+                idle_keepalive_seconds: app_config.idle_keepalive_seconds,
+                max_concurrent_accounts: app_config.max_concurrent_accounts,
             };
 
             server::start_server(state, port, app_config.fetch_on_startup).await?;
         }
+        Some("dedupe") => {
+            run_dedupe(&db, &output_dir)?;
+        }
+        Some("verify") => {
+            let repair = match args.get(2).map(String::as_str) {
+                None => false,
+                Some("--repair") => true,
+                Some(other) => return Err(anyhow::anyhow!("Unknown verify option: {}", other)),
+            };
+            run_verify(
+                &accounts,
+                &output_dir,
+                &db,
+                app_config.max_concurrent_accounts,
+                repair,
+            )
+            .await?;
+        }
+        Some("reindex") => {
+            run_reindex(&db, &accounts, &output_dir)?;
+        }
         Some(cmd) => {
             eprintln!("Unknown command: {}", cmd);
-            eprintln!("Usage: courrier [fetch|server] [port]");
-            eprintln!("  fetch  - Run one-time fetch and exit");
+            eprintln!("Usage: courrier [fetch|server|dedupe|verify|reindex] [port]");
+            eprintln!("  fetch  - Run one-time fetch and exit, optionally limited with");
+            eprintln!("           --account <email> --mailbox <name or pattern>");
+            eprintln!("           --since <YYYY-MM-DD> --before <YYYY-MM-DD> --failed-only");
             eprintln!("  server - Start web dashboard (default)");
+            eprintln!("  dedupe - Move the archive into the content-addressed blob store");
+            eprintln!("  verify - Check the archive files against the database, and with");
+            eprintln!("           --repair re-fetch broken messages and re-index orphans");
+            eprintln!("  reindex - Rebuild the database from the archived files, checking");
+            eprintln!("            UIDVALIDITY with the server when it can be reached");
             eprintln!("  port   - Port number for server (default: 3000)");
             std::process::exit(1);
         }
@@ -63,17 +123,117 @@ async fn run_fetch(
     accounts: &[config::AccountConfig],
     output_dir: &Path,
     db: &database::Database,
+    max_concurrent_accounts: usize,
+    scope: &fetch_scope::FetchScope,
+) -> Result<()> {
+    info!(%scope, "Starting fetch operation");
+
+    let total_saved = fetcher::fetch_all_accounts(
+        accounts,
+        output_dir,
+        db,
+        max_concurrent_accounts,
+        scope,
+        database::FetchTrigger::Cli,
+        &events::EventBus::new(),
+        control::FetchControl::default(),
+    )
+    .await?;
+
+    info!(
+        messages_saved = total_saved,
+        path = %output_dir.display(),
+        "Fetch operation done"
+    );
+
+    Ok(())
+}
+
+fn run_dedupe(db: &database::Database, output_dir: &Path) -> Result<()> {
+    info!("Moving archived messages into the blob store");
+
+    let report = blob_store::dedupe_archive(db, output_dir)?;
+
+    info!(
+        files_scanned = report.files_scanned,
+        blobs_created = report.blobs_created,
+        duplicates_removed = report.duplicates_removed,
+        missing_files = report.missing_files,
+        bytes_saved = report.bytes_saved,
+        "Dedupe done, saved {:.2} MiB",
+        report.bytes_saved as f64 / (1024.0 * 1024.0)
+    );
+
+    Ok(())
+}
+
+fn run_reindex(
+    db: &database::Database,
+    accounts: &[config::AccountConfig],
+    output_dir: &Path,
 ) -> Result<()> {
-    println!("\n{}", "=".repeat(80));
-    println!("Starting fetch operation");
-    println!("{}", "=".repeat(80));
+    info!(path = %output_dir.display(), "Rebuilding the database from the archive");
+
+    let report = reindex::reindex_archive(db, accounts, output_dir)?;
+
+    if report.mismatched > 0 || report.stale_generations > 0 {
+        info!(
+            mismatched = report.mismatched,
+            stale_generations = report.stale_generations,
+            "Run `courrier fetch` to download the messages that differ from the archive"
+        );
+    }
+    if report.unverified_mailboxes > 0 {
+        info!(
+            unverified_mailboxes = report.unverified_mailboxes,
+            "The next fetch adopts these mailboxes into the server's UIDVALIDITY as they are"
+        );
+    }
 
-    let total_saved = fetcher::fetch_all_accounts(accounts, output_dir, db).await?;
+    Ok(())
+}
+
+async fn run_verify(
+    accounts: &[config::AccountConfig],
+    output_dir: &Path,
+    db: &database::Database,
+    max_concurrent_accounts: usize,
+    repair: bool,
+) -> Result<()> {
+    info!(repair, "Verifying the archive");
 
-    println!("\n{}", "=".repeat(80));
-    println!("✓ Done! Total messages saved: {}", total_saved);
-    println!("Messages saved to: {}", output_dir.display());
-    println!("{}", "=".repeat(80));
+    let report = verify::verify_archive(db, accounts, output_dir, repair)?;
+    if report.refetch_scheduled > 0 {
+        let scope = fetch_scope::FetchScope {
+            failed_only: true,
+            ..Default::default()
+        };
+        run_fetch(accounts, output_dir, db, max_concurrent_accounts, &scope).await?;
+    }
 
+    if repair {
+        info!(
+            refetch_scheduled = report.refetch_scheduled,
+            reindexed = report.reindexed,
+            quarantined = report.quarantined,
+            temp_files_deleted = report.temp_files_deleted,
+            unrepairable = report.unrepairable,
+            "Repair done"
+        );
+    }
+    // Problems that weren't repaired fail the command, so scripts and cron jobs notice
+    let unresolved = report.unresolved();
+    if unresolved > 0 {
+        return Err(anyhow::anyhow!(
+            "{} problem(s) {}",
+            unresolved,
+            if repair {
+                "in the archive could not be repaired"
+            } else {
+                "found in the archive, run with --repair to fix them"
+            }
+        ));
+    }
+    info!("The archive matches the database");
     Ok(())
 }
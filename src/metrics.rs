@@ -0,0 +1,215 @@
+use crate::config::AccountConfig;
+use crate::database::{Database, RUN_DURATION_BUCKETS};
+use anyhow::Result;
+use prometheus::proto::{self, MetricFamily, MetricType};
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+/// Renders the metrics in the Prometheus text format. They are read from the database on
+/// every scrape, the same data `/api/stats` and `/api/fetch/history` are built from, so they
+/// survive restarts and include fetches run with `courrier fetch`.
+pub fn render(db: &Database, accounts: &[AccountConfig]) -> Result<String> {
+    let registry = Registry::new();
+
+    let messages_fetched = register(
+        &registry,
+        IntCounterVec::new(
+            Opts::new(
+                "courrier_messages_fetched_total",
+                "Messages downloaded from the server",
+            ),
+            &["account", "mailbox"],
+        )?,
+    )?;
+    let bytes_fetched = register(
+        &registry,
+        IntCounterVec::new(
+            Opts::new(
+                "courrier_bytes_fetched_total",
+                "Bytes of messages downloaded from the server",
+            ),
+            &["account", "mailbox"],
+        )?,
+    )?;
+    let failures = register(
+        &registry,
+        IntCounterVec::new(
+            Opts::new(
+                "courrier_fetch_failures_total",
+                "Failed messages (class \"message\") and failed mailbox or account syncs",
+            ),
+            &["account", "class"],
+        )?,
+    )?;
+    for totals in db.get_fetch_totals()? {
+        let labels = [totals.account_email.as_str(), totals.mailbox.as_str()];
+        messages_fetched
+            .with_label_values(&labels)
+            .inc_by(totals.messages_fetched.max(0) as u64);
+        bytes_fetched
+            .with_label_values(&labels)
+            .inc_by(totals.bytes_fetched.max(0) as u64);
+        if totals.messages_failed > 0 {
+            failures
+                .with_label_values(&[&totals.account_email, "message"])
+                .inc_by(totals.messages_failed as u64);
+        }
+    }
+    for (account, class, count) in db.get_fetch_errors()? {
+        failures
+            .with_label_values(&[&account, &class])
+            .inc_by(count.max(0) as u64);
+    }
+
+    // Configured accounts that never synced report 0, so an alert on the sync age fires for
+    // them too
+    let last_sync = register(
+        &registry,
+        IntGaugeVec::new(
+            Opts::new(
+                "courrier_last_successful_sync_timestamp_seconds",
+                "Unix time a mailbox of the account was last synced, 0 if never",
+            ),
+            &["account"],
+        )?,
+    )?;
+    let syncs = db.get_last_successful_syncs()?;
+    for account in accounts {
+        last_sync.with_label_values(&[&account.email]).set(0);
+    }
+    for (account, completed_at) in syncs {
+        last_sync
+            .with_label_values(&[&account])
+            .set(completed_at.timestamp());
+    }
+
+    let archived_messages = register(
+        &registry,
+        IntGaugeVec::new(
+            Opts::new("courrier_archived_messages", "Messages in the archive"),
+            &["account", "mailbox"],
+        )?,
+    )?;
+    let archived_bytes = register(
+        &registry,
+        IntGaugeVec::new(
+            Opts::new(
+                "courrier_archived_bytes",
+                "Size of the messages in the archive",
+            ),
+            &["account", "mailbox"],
+        )?,
+    )?;
+    let deleted_on_server = register(
+        &registry,
+        IntGaugeVec::new(
+            Opts::new(
+                "courrier_deleted_on_server_messages",
+                "Archived messages that no longer exist on the server",
+            ),
+            &["account", "mailbox"],
+        )?,
+    )?;
+    for stats in db.get_stats()? {
+        let labels = [stats.account_email.as_str(), stats.mailbox.as_str()];
+        archived_messages
+            .with_label_values(&labels)
+            .set(stats.count);
+        archived_bytes
+            .with_label_values(&labels)
+            .set(stats.total_size_bytes);
+        deleted_on_server
+            .with_label_values(&labels)
+            .set(stats.deleted_on_server);
+    }
+
+    // Messages stored once but listed in several mailboxes count once here
+    let (stored_messages, stored_bytes) = db.get_total_stats()?;
+    register(
+        &registry,
+        IntGauge::new(
+            "courrier_archive_stored_messages",
+            "Distinct messages stored in the archive",
+        )?,
+    )?
+    .set(stored_messages);
+    register(
+        &registry,
+        IntGauge::new(
+            "courrier_archive_size_bytes",
+            "Size of the distinct messages stored in the archive",
+        )?,
+    )?
+    .set(stored_bytes);
+
+    let mut families = registry.gather();
+    families.push(run_duration_histogram(&db.get_fetch_run_durations()?));
+    Ok(TextEncoder::new().encode_to_string(&families)?)
+}
+
+/// The run duration histogram, built from the bucket counts the database keeps rather than
+/// by observing every run again.
+fn run_duration_histogram(buckets: &[(f64, i64, f64)]) -> MetricFamily {
+    let mut histogram = proto::Histogram::default();
+    histogram.set_sample_count(buckets.iter().map(|(_, runs, _)| *runs as u64).sum());
+    histogram.set_sample_sum(buckets.iter().map(|(_, _, seconds)| seconds).sum());
+    histogram.set_bucket(
+        RUN_DURATION_BUCKETS
+            .iter()
+            .map(|bound| {
+                let mut bucket = proto::Bucket::default();
+                bucket.set_upper_bound(*bound);
+                bucket.set_cumulative_count(
+                    buckets
+                        .iter()
+                        .filter(|(upper_bound, _, _)| upper_bound <= bound)
+                        .map(|(_, runs, _)| *runs as u64)
+                        .sum(),
+                );
+                bucket
+            })
+            .collect(),
+    );
+
+    let mut metric = proto::Metric::default();
+    metric.set_histogram(histogram);
+    let mut family = MetricFamily::default();
+    family.set_name("courrier_fetch_run_duration_seconds".to_string());
+    family.set_help("Duration of finished fetch runs".to_string());
+    family.set_field_type(MetricType::HISTOGRAM);
+    family.set_metric(vec![metric]);
+    family
+}
+
+fn register<M>(registry: &Registry, metric: M) -> Result<M>
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    registry.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_duration_buckets_are_cumulative() {
+        let family =
+            run_duration_histogram(&[(1.0, 2, 1.5), (60.0, 1, 42.0), (f64::INFINITY, 1, 20_000.0)]);
+        let histogram = family.get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_sample_count(), 4);
+        assert_eq!(histogram.get_sample_sum(), 20_043.5);
+        let cumulative: Vec<(f64, u64)> = histogram
+            .get_bucket()
+            .iter()
+            .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+            .collect();
+        assert_eq!(cumulative[0], (1.0, 2));
+        assert_eq!(cumulative[3], (30.0, 2));
+        assert_eq!(cumulative[4], (60.0, 3));
+        assert_eq!(cumulative.last(), Some(&(14400.0, 3)));
+
+        let text = TextEncoder::new().encode_to_string(&[family]).unwrap();
+        assert!(text.contains("courrier_fetch_run_duration_seconds_bucket{le=\"+Inf\"} 4"));
+    }
+}
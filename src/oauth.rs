@@ -0,0 +1,276 @@
+use crate::config::{AccountConfig, OAuth2Config};
+use crate::retry::AuthError;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const MICROSOFT_TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+
+/// Refresh tokens this long before the server-reported expiry.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+    /// Providers like Microsoft rotate refresh tokens, the newest one wins over the config
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+fn token_cache() -> &'static Mutex<HashMap<String, CachedToken>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, CachedToken>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn token_url(config: &AccountConfig, oauth2: &OAuth2Config) -> Result<String> {
+    if let Some(url) = &oauth2.token_url {
+        return Ok(url.clone());
+    }
+
+    match config.server.as_str() {
+        "imap.gmail.com" => Ok(GOOGLE_TOKEN_URL.to_string()),
+        "outlook.office365.com" | "imap-mail.outlook.com" => Ok(MICROSOFT_TOKEN_URL.to_string()),
+        _ => Err(anyhow::anyhow!(
+            "No token_url configured for OAuth2 account {} on {}",
+            config.email,
+            config.server
+        )),
+    }
+}
+
+/// Returns a valid access token for the account, refreshing it through the token endpoint
+/// when the cached one is missing or about to expire. Blocking, call from blocking tasks.
+pub fn access_token(config: &AccountConfig) -> Result<String> {
+    let oauth2 = config.oauth2.as_ref().ok_or_else(|| {
+        anyhow::anyhow!(
+            "OAuth2 account {} needs client_id and refresh_token",
+            config.email
+        )
+    })?;
+
+    let refresh_token = {
+        let cache = token_cache().lock().unwrap();
+        match cache.get(&config.email) {
+            Some(token) if token.expires_at > Instant::now() + EXPIRY_MARGIN => {
+                return Ok(token.access_token.clone());
+            }
+            Some(token) => token.refresh_token.clone(),
+            None => oauth2.refresh_token.clone(),
+        }
+    };
+
+    let url = token_url(config, oauth2)?;
+    info!(account = %config.email, "Refreshing OAuth2 access token");
+
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("client_id", oauth2.client_id.as_str()),
+        ("refresh_token", refresh_token.as_str()),
+    ];
+    if let Some(secret) = &oauth2.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+        .timeout(Duration::from_secs(30))
+        .build();
+    let body = match agent.post(&url).send_form(&form) {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(code, response)) => {
+            let message = format!(
+                "Token refresh for {} failed with HTTP {}: {}",
+                config.email,
+                code,
+                response.into_string().unwrap_or_default()
+            );
+            // A revoked or invalid refresh token won't work on a retry either
+            return Err(match code {
+                400 | 401 => AuthError(message).into(),
+                _ => anyhow::anyhow!(message),
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let token: TokenResponse = serde_json::from_str(&body)?;
+
+    let access_token = token.access_token.clone();
+    token_cache().lock().unwrap().insert(
+        config.email.clone(),
+        CachedToken {
+            access_token: token.access_token,
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in.unwrap_or(3600)),
+            refresh_token: token.refresh_token.unwrap_or(refresh_token),
+        },
+    );
+
+    Ok(access_token)
+}
+
+/// Drops the cached access token, e.g. after the server rejected it.
+pub fn invalidate(config: &AccountConfig) {
+    if let Some(token) = token_cache().lock().unwrap().get_mut(&config.email) {
+        token.expires_at = Instant::now();
+    }
+}
+
+/// SASL XOAUTH2 as used by Gmail and Outlook.
+pub struct XOAuth2 {
+    pub user: String,
+    pub access_token: String,
+}
+
+impl imap::Authenticator for XOAuth2 {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        // A non-empty challenge carries the error details, answer with an empty response
+        if !challenge.is_empty() {
+            return String::new();
+        }
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        )
+    }
+}
+
+/// SASL OAUTHBEARER (RFC 7628).
+pub struct OAuthBearer {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub access_token: String,
+}
+
+impl imap::Authenticator for OAuthBearer {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        if !challenge.is_empty() {
+            return "\x01".to_string();
+        }
+        format!(
+            "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.host, self.port, self.access_token
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthMethod, SaslMechanism};
+    use crate::retry::ErrorClass;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    fn account(email: &str, token_url: String) -> AccountConfig {
+        AccountConfig {
+            auth: AuthMethod::OAuth2,
+            oauth2: Some(OAuth2Config {
+                client_id: "client".to_string(),
+                client_secret: Some("secret".to_string()),
+                refresh_token: "refresh-1".to_string(),
+                token_url: Some(token_url),
+                mechanism: SaslMechanism::XOAuth2,
+            }),
+            ..AccountConfig::for_test(email)
+        }
+    }
+
+    /// Answers one HTTP request with `status` and `body`, returning the URL to send it to and
+    /// the request body it got.
+    fn serve_once(status: &'static str, body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request = vec![0; content_length];
+            reader.read_exact(&mut request).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn refreshes_and_caches_the_access_token() {
+        let (url, server) = serve_once(
+            "200 OK",
+            r#"{"access_token":"access-1","expires_in":3600,"refresh_token":"refresh-2"}"#,
+        );
+        let config = account("refresh@example.com", url);
+
+        assert_eq!(access_token(&config).unwrap(), "access-1");
+        let request = server.join().unwrap();
+        assert!(request.contains("grant_type=refresh_token"));
+        assert!(request.contains("client_id=client"));
+        assert!(request.contains("client_secret=secret"));
+        assert!(request.contains("refresh_token=refresh-1"));
+
+        // The listener is gone, so this can only come from the cache
+        assert_eq!(access_token(&config).unwrap(), "access-1");
+    }
+
+    #[test]
+    fn uses_the_rotated_refresh_token_after_invalidation() {
+        let (url, server) = serve_once(
+            "200 OK",
+            r#"{"access_token":"access-1","refresh_token":"refresh-2"}"#,
+        );
+        let mut config = account("rotate@example.com", url);
+        access_token(&config).unwrap();
+        server.join().unwrap();
+
+        invalidate(&config);
+        let (url, server) = serve_once("200 OK", r#"{"access_token":"access-2"}"#);
+        config.oauth2.as_mut().unwrap().token_url = Some(url);
+        assert_eq!(access_token(&config).unwrap(), "access-2");
+        assert!(server.join().unwrap().contains("refresh_token=refresh-2"));
+    }
+
+    #[test]
+    fn rejected_refresh_token_is_an_auth_error() {
+        let (url, server) = serve_once("400 Bad Request", r#"{"error":"invalid_grant"}"#);
+        let config = account("rejected@example.com", url);
+
+        let error = access_token(&config).unwrap_err();
+        server.join().unwrap();
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Auth);
+        assert!(error.to_string().contains("invalid_grant"));
+    }
+}
@@ -0,0 +1,624 @@
+use crate::atomic_file;
+use crate::blob_store;
+use crate::config::AccountConfig;
+use crate::database::{Database, FetchedMessage, MessageAttributes};
+use crate::fetcher::{self, ServerMailbox, GMAIL_STORE_DIR};
+use crate::verify::{self, MessageLocation};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Outcome of `reindex_archive`.
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    pub files_scanned: usize,
+    /// Rows added to `fetched_emails`
+    pub messages_indexed: usize,
+    /// Files that already had a row
+    pub already_indexed: usize,
+    /// Files whose size differs from the message the server has under that UID. They are moved
+    /// to `.quarantine/` before the next fetch downloads the message again in their place.
+    pub mismatched: usize,
+    /// Mailboxes whose files belong to an older UIDVALIDITY than the server's, moved to
+    /// `.uidvalidity-0/`
+    pub stale_generations: usize,
+    /// Mailboxes indexed without knowing their UIDVALIDITY, because the server couldn't be
+    /// asked. The next fetch adopts them into the server's current one.
+    pub unverified_mailboxes: usize,
+    /// Files whose message can't be told from their path: blob store and trash files, files
+    /// of unconfigured accounts, and Gmail messages that no mailbox holds anymore
+    pub not_indexed: usize,
+}
+
+/// An archived file to record, with everything the row needs.
+struct Entry {
+    uid: u32,
+    file_path: PathBuf,
+    size_bytes: usize,
+    sha256: String,
+    attributes: MessageAttributes,
+    gmail: Option<(u64, Vec<String>)>,
+}
+
+/// The message files of one account found under `email_storage_path`.
+#[derive(Default)]
+struct AccountFiles {
+    /// `<mailbox>/<uid>.eml` files by mailbox, in the mailbox's current generation
+    current: BTreeMap<String, Vec<(u32, PathBuf)>>,
+    /// `<mailbox>/.uidvalidity-<n>/<uid>.eml` files by mailbox and generation
+    archived: BTreeMap<(String, u32), Vec<(u32, PathBuf)>>,
+    /// `gmail-messages/<msgid>.eml` files by X-GM-MSGID
+    gmail: HashMap<u64, PathBuf>,
+}
+
+/// Rebuilds `fetched_emails` (and the Gmail and mailbox state tables) from the files under
+/// `output_dir`, for when the database was lost. Files that already have a row are left
+/// alone, so it can be run on a partial database too.
+///
+/// The UID and mailbox of a message come from its path. The UIDVALIDITY a mailbox's files
+/// belong to is checked with the server: if most archived UIDs still name messages of the
+/// same size there, the files are recorded in the server's current generation; otherwise
+/// they are moved to `.uidvalidity-0/` as an old generation and the mailbox is downloaded
+/// again by the next fetch. Gmail messages are matched to their mailboxes by X-GM-MSGID.
+/// Without a connection, mailboxes are recorded with an unknown UIDVALIDITY and Gmail
+/// messages are left out. Blocking.
+pub fn reindex_archive(
+    db: &Database,
+    accounts: &[AccountConfig],
+    output_dir: &Path,
+) -> Result<ReindexReport> {
+    let mut report = ReindexReport::default();
+
+    let mut files = Vec::new();
+    verify::collect_files(
+        output_dir,
+        &output_dir.join(verify::QUARANTINE_DIR),
+        &mut files,
+    )?;
+    let mut by_account: HashMap<String, AccountFiles> = HashMap::new();
+    for file in files {
+        if atomic_file::is_temp_file(&file) || file.extension().is_none_or(|ext| ext != "eml") {
+            continue;
+        }
+        report.files_scanned += 1;
+        if let Some(location) = MessageLocation::parse(accounts, output_dir, &file) {
+            let account = by_account.entry(location.account_email).or_default();
+            match location.uid_validity {
+                Some(uid_validity) => account
+                    .archived
+                    .entry((location.mailbox, uid_validity))
+                    .or_default()
+                    .push((location.uid, file)),
+                None => account
+                    .current
+                    .entry(location.mailbox)
+                    .or_default()
+                    .push((location.uid, file)),
+            }
+        } else if let Some((account_email, msgid)) = gmail_location(accounts, output_dir, &file) {
+            by_account
+                .entry(account_email)
+                .or_default()
+                .gmail
+                .insert(msgid, file);
+        } else {
+            report.not_indexed += 1;
+        }
+    }
+
+    for account in accounts {
+        let Some(files) = by_account.remove(&account.email) else {
+            continue;
+        };
+        info!(account = %account.email, "Re-indexing account");
+        reindex_account(db, account, output_dir, files, &mut report)?;
+    }
+
+    info!(
+        files = report.files_scanned,
+        indexed = report.messages_indexed,
+        already_indexed = report.already_indexed,
+        mismatched = report.mismatched,
+        stale_generations = report.stale_generations,
+        unverified_mailboxes = report.unverified_mailboxes,
+        not_indexed = report.not_indexed,
+        "Re-indexing done"
+    );
+    Ok(report)
+}
+
+fn reindex_account(
+    db: &Database,
+    account: &AccountConfig,
+    output_dir: &Path,
+    files: AccountFiles,
+    report: &mut ReindexReport,
+) -> Result<()> {
+    // Only mailboxes the database knows nothing about need their generation checked
+    let mut unknown: HashMap<String, HashSet<u32>> = HashMap::new();
+    for (mailbox, mailbox_files) in &files.current {
+        if db.get_mailbox_state(&account.email, mailbox)?.is_none() {
+            unknown.insert(
+                mailbox.clone(),
+                mailbox_files.iter().map(|(uid, _)| *uid).collect(),
+            );
+        }
+    }
+    for (mailbox, uid_validity) in files.archived.keys() {
+        if *uid_validity == 0 && db.get_mailbox_state(&account.email, mailbox)?.is_none() {
+            unknown.entry(mailbox.clone()).or_default();
+        }
+    }
+    let server = if unknown.is_empty() && files.gmail.is_empty() {
+        None
+    } else {
+        match fetcher::describe_server_mailboxes(account, &unknown) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                warn!(
+                    error = %format!("{:#}", e),
+                    "Server unreachable, indexing without checking UIDVALIDITY"
+                );
+                None
+            }
+        }
+    };
+
+    for (mailbox, mailbox_files) in &files.current {
+        if let Some(state) = db.get_mailbox_state(&account.email, mailbox)? {
+            let entries = read_entries(mailbox_files)?;
+            record(
+                db,
+                &account.email,
+                mailbox,
+                state.uid_validity,
+                entries,
+                report,
+            )?;
+            continue;
+        }
+        let Some(server_mailbox) = server.as_ref().and_then(|server| server.get(mailbox)) else {
+            // Recorded with UIDVALIDITY 0 and no mailbox state, like archives from before
+            // UIDVALIDITY was tracked, so the next fetch adopts them
+            warn!(mailbox = %mailbox, "Indexing mailbox without its UIDVALIDITY");
+            report.unverified_mailboxes += 1;
+            let entries = read_entries(mailbox_files)?;
+            record(db, &account.email, mailbox, 0, entries, report)?;
+            continue;
+        };
+        reconcile_mailbox(
+            db,
+            account,
+            output_dir,
+            mailbox,
+            mailbox_files,
+            server_mailbox,
+            report,
+        )?;
+    }
+
+    for ((mailbox, uid_validity), mailbox_files) in &files.archived {
+        // Rows of UIDVALIDITY 0 in a mailbox without state would be adopted into the
+        // current generation by the next fetch, so the mailbox's state has to be known first
+        if *uid_validity == 0 && db.get_mailbox_state(&account.email, mailbox)?.is_none() {
+            match server.as_ref().and_then(|server| server.get(mailbox)) {
+                Some(server_mailbox) => db.set_mailbox_uid_validity(
+                    &account.email,
+                    mailbox,
+                    server_mailbox.uid_validity,
+                )?,
+                None => {
+                    warn!(
+                        mailbox = %mailbox,
+                        "Not indexing an old generation of unknown UIDVALIDITY without the server"
+                    );
+                    report.not_indexed += mailbox_files.len();
+                    continue;
+                }
+            }
+        }
+        let entries = read_entries(mailbox_files)?;
+        record(db, &account.email, mailbox, *uid_validity, entries, report)?;
+    }
+
+    let Some(server) = server else {
+        report.not_indexed += files.gmail.len();
+        return Ok(());
+    };
+    let mut linked: HashSet<u64> = HashSet::new();
+    for (mailbox, server_mailbox) in &server {
+        let mut entries = Vec::new();
+        for (uid, metadata) in &server_mailbox.gmail {
+            let Some(file) = files.gmail.get(&metadata.msgid) else {
+                continue;
+            };
+            let body = fs::read(file)?;
+            entries.push(Entry {
+                uid: *uid,
+                file_path: file.clone(),
+                size_bytes: body.len(),
+                sha256: blob_store::sha256_hex(&body),
+                attributes: metadata.attributes.clone(),
+                gmail: Some((metadata.msgid, metadata.labels.clone())),
+            });
+            linked.insert(metadata.msgid);
+        }
+        if entries.is_empty() {
+            continue;
+        }
+        match db.get_mailbox_state(&account.email, mailbox)? {
+            None => {
+                db.set_mailbox_uid_validity(&account.email, mailbox, server_mailbox.uid_validity)?
+            }
+            // The UIDs belong to the server's generation, the next fetch moves to it first
+            Some(state) if state.uid_validity != server_mailbox.uid_validity => continue,
+            Some(_) => {}
+        }
+        record(
+            db,
+            &account.email,
+            mailbox,
+            server_mailbox.uid_validity,
+            entries,
+            report,
+        )?;
+    }
+    let unlinked = files
+        .gmail
+        .keys()
+        .filter(|msgid| !linked.contains(msgid))
+        .count();
+    if unlinked > 0 {
+        warn!(
+            count = unlinked,
+            "Gmail messages not found in any mailbox on the server"
+        );
+        report.not_indexed += unlinked;
+    }
+    Ok(())
+}
+
+/// Decides which UIDVALIDITY generation the files of a mailbox the database doesn't know
+/// belong to, by comparing their sizes with the messages under the same UIDs on the server.
+fn reconcile_mailbox(
+    db: &Database,
+    account: &AccountConfig,
+    output_dir: &Path,
+    mailbox: &str,
+    mailbox_files: &[(u32, PathBuf)],
+    server_mailbox: &ServerMailbox,
+    report: &mut ReindexReport,
+) -> Result<()> {
+    let entries = read_entries(mailbox_files)?;
+    let (mut matching, mismatched): (Vec<Entry>, Vec<Entry>) =
+        entries.into_iter().partition(|entry| {
+            server_mailbox
+                .messages
+                .get(&entry.uid)
+                .is_none_or(|(size, _)| *size as usize == entry.size_bytes)
+        });
+    let matched = matching
+        .iter()
+        .filter(|entry| server_mailbox.messages.contains_key(&entry.uid))
+        .count();
+
+    if mismatched.len() > matched {
+        // The UIDs name other messages now: keep the files as an old generation of unknown
+        // UIDVALIDITY, the way the fetcher does when it sees UIDVALIDITY change
+        let mailbox_dir = output_dir
+            .join(account.email.replace("@", "_"))
+            .join(mailbox);
+        let archive_dir = mailbox_dir.join(format!("{}0", fetcher::UID_VALIDITY_DIR_PREFIX));
+        if archive_dir.exists() {
+            anyhow::bail!(
+                "{} belongs to an older UIDVALIDITY, but {} is already taken. Move it away and \
+                 run reindex again",
+                mailbox_dir.display(),
+                archive_dir.display()
+            );
+        }
+        warn!(
+            mailbox,
+            matched,
+            mismatched = mismatched.len(),
+            uid_validity = server_mailbox.uid_validity,
+            "Files belong to an older UIDVALIDITY, archiving them"
+        );
+        report.stale_generations += 1;
+        let entries = matching.into_iter().chain(mismatched).collect();
+        record(db, &account.email, mailbox, 0, entries, report)?;
+        let archive_dir = fetcher::archive_uid_validity_generation(&mailbox_dir, 0)?;
+        db.start_uid_validity_generation(
+            &account.email,
+            mailbox,
+            0,
+            server_mailbox.uid_validity,
+            &archive_dir,
+        )?;
+        return Ok(());
+    }
+
+    for entry in &mismatched {
+        warn!(
+            mailbox,
+            uid = entry.uid,
+            path = %entry.file_path.display(),
+            "File differs from the message on the server, moving it aside"
+        );
+        verify::quarantine(output_dir, &entry.file_path)?;
+    }
+    report.mismatched += mismatched.len();
+    for entry in &mut matching {
+        if let Some((_, attributes)) = server_mailbox.messages.get(&entry.uid) {
+            entry.attributes.flags = attributes.flags.clone();
+            if attributes.internal_date.is_some() {
+                entry.attributes.internal_date = attributes.internal_date;
+            }
+        }
+    }
+    db.set_mailbox_uid_validity(&account.email, mailbox, server_mailbox.uid_validity)?;
+    record(
+        db,
+        &account.email,
+        mailbox,
+        server_mailbox.uid_validity,
+        matching,
+        report,
+    )
+}
+
+/// Reads the files of a mailbox for their size and SHA-256. The INTERNALDATE is taken from
+/// the file's modification time, which the fetcher set it to. Flags are left empty for the
+/// server's answer or the next fetch to fill in.
+fn read_entries(files: &[(u32, PathBuf)]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::with_capacity(files.len());
+    for (uid, file) in files {
+        let body = fs::read(file)?;
+        let attributes = MessageAttributes {
+            flags: String::new(),
+            internal_date: fs::metadata(file)?
+                .modified()
+                .ok()
+                .map(|modified| DateTime::<Utc>::from(modified).fixed_offset()),
+        };
+        entries.push(Entry {
+            uid: *uid,
+            file_path: file.clone(),
+            size_bytes: body.len(),
+            sha256: blob_store::sha256_hex(&body),
+            attributes,
+            gmail: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// Records the entries of a mailbox generation in one transaction, except the UIDs that
+/// already have a row.
+fn record(
+    db: &Database,
+    account_email: &str,
+    mailbox: &str,
+    uid_validity: u32,
+    mut entries: Vec<Entry>,
+    report: &mut ReindexReport,
+) -> Result<()> {
+    let existing: HashSet<u32> = db
+        .get_fetched_uids(account_email, mailbox, uid_validity)?
+        .into_iter()
+        .collect();
+    let before = entries.len();
+    entries.retain(|entry| !existing.contains(&entry.uid));
+    report.already_indexed += before - entries.len();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let messages: Vec<FetchedMessage> = entries
+        .iter()
+        .map(|entry| FetchedMessage {
+            uid: entry.uid,
+            file_path: &entry.file_path,
+            size_bytes: entry.size_bytes,
+            sha256: Some(&entry.sha256),
+            attributes: &entry.attributes,
+            gmail: entry
+                .gmail
+                .as_ref()
+                .map(|(msgid, labels)| (*msgid, labels.as_slice())),
+        })
+        .collect();
+    db.record_fetched_messages(account_email, mailbox, uid_validity, &messages)?;
+    info!(
+        mailbox,
+        uid_validity,
+        indexed = messages.len(),
+        "Indexed messages"
+    );
+    report.messages_indexed += messages.len();
+    Ok(())
+}
+
+/// The account and X-GM-MSGID of a `<account>/gmail-messages/<msgid>.eml` file.
+fn gmail_location(
+    accounts: &[AccountConfig],
+    output_dir: &Path,
+    file: &Path,
+) -> Option<(String, u64)> {
+    let relative = file.strip_prefix(output_dir).ok()?;
+    let mut parts = relative.components().map(|part| part.as_os_str());
+    let account_dir = parts.next()?.to_string_lossy();
+    if parts.next()? != GMAIL_STORE_DIR {
+        return None;
+    }
+    let msgid = parts
+        .next()?
+        .to_string_lossy()
+        .strip_suffix(".eml")?
+        .parse()
+        .ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let account = accounts
+        .iter()
+        .find(|account| account.email.replace("@", "_") == account_dir)?;
+    Some((account.email.clone(), msgid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::TestDb;
+
+    /// An empty archive directory of its own for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("courrier-reindex-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes message `uid` of `mailbox` (optionally under a generation directory) with a
+    /// body `size` bytes long.
+    fn write_message(mailbox_dir: &Path, uid: u32, size: usize) -> PathBuf {
+        fs::create_dir_all(mailbox_dir).unwrap();
+        let path = mailbox_dir.join(format!("{}.eml", uid));
+        fs::write(&path, "x".repeat(size)).unwrap();
+        path
+    }
+
+    /// The server's mailbox with UIDVALIDITY 9 and the given UIDs and sizes.
+    fn server_mailbox(messages: &[(u32, u32)]) -> ServerMailbox {
+        ServerMailbox {
+            uid_validity: 9,
+            messages: messages
+                .iter()
+                .map(|(uid, size)| (*uid, (*size, MessageAttributes::default())))
+                .collect(),
+            gmail: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn rebuilds_rows_from_the_files_without_a_server() {
+        let test_db = TestDb::new("reindex-rebuild");
+        let db = test_db.open();
+        let dir = test_dir("rebuild");
+        let inbox = dir.join("me_example.com").join("INBOX");
+        write_message(&inbox, 1, 10);
+        write_message(&inbox, 2, 20);
+        write_message(&inbox.join(".uidvalidity-5"), 1, 30);
+        fs::write(dir.join("notes.txt"), "not a message").unwrap();
+
+        // Nothing listens on port 1, so the UIDVALIDITY can't be checked
+        let mut account = AccountConfig::for_test("me@example.com");
+        account.server = "127.0.0.1".to_string();
+        account.port = 1;
+        account.retry.connect.max_attempts = 1;
+        account.retry.login.max_attempts = 1;
+        let accounts = [account];
+
+        let report = reindex_archive(&db, &accounts, &dir).unwrap();
+        assert_eq!(report.files_scanned, 3);
+        assert_eq!(report.messages_indexed, 3);
+        assert_eq!(report.unverified_mailboxes, 1);
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 0).unwrap(),
+            [1, 2]
+        );
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 5).unwrap(),
+            [1]
+        );
+        assert!(db
+            .get_mailbox_state("me@example.com", "INBOX")
+            .unwrap()
+            .is_none());
+
+        // Files that already have a row are left alone
+        let report = reindex_archive(&db, &accounts, &dir).unwrap();
+        assert_eq!(report.messages_indexed, 0);
+        assert_eq!(report.already_indexed, 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn archives_files_of_an_older_generation() {
+        let test_db = TestDb::new("reindex-stale");
+        let db = test_db.open();
+        let dir = test_dir("stale");
+        let account = AccountConfig::for_test("me@example.com");
+        let inbox = dir.join("me_example.com").join("INBOX");
+        let files = vec![
+            (1, write_message(&inbox, 1, 10)),
+            (2, write_message(&inbox, 2, 20)),
+            (3, write_message(&inbox, 3, 30)),
+        ];
+
+        // The server has other messages under most of these UIDs
+        let server = server_mailbox(&[(1, 10), (2, 21), (3, 31)]);
+        let mut report = ReindexReport::default();
+        reconcile_mailbox(&db, &account, &dir, "INBOX", &files, &server, &mut report).unwrap();
+
+        assert_eq!(report.stale_generations, 1);
+        assert_eq!(report.mismatched, 0);
+        assert!(!inbox.join("1.eml").exists());
+        for uid in 1..=3 {
+            assert!(inbox
+                .join(".uidvalidity-0")
+                .join(format!("{}.eml", uid))
+                .exists());
+        }
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 0).unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            db.get_mailbox_state("me@example.com", "INBOX")
+                .unwrap()
+                .unwrap()
+                .uid_validity,
+            9
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn quarantines_a_file_that_differs_from_the_server() {
+        let test_db = TestDb::new("reindex-quarantine");
+        let db = test_db.open();
+        let dir = test_dir("quarantine");
+        let account = AccountConfig::for_test("me@example.com");
+        let inbox = dir.join("me_example.com").join("INBOX");
+        let files = vec![
+            (1, write_message(&inbox, 1, 10)),
+            (2, write_message(&inbox, 2, 20)),
+            (3, write_message(&inbox, 3, 30)),
+        ];
+
+        // UID 3 was deleted on the server, UID 2 has another size there
+        let server = server_mailbox(&[(1, 10), (2, 25)]);
+        let mut report = ReindexReport::default();
+        reconcile_mailbox(&db, &account, &dir, "INBOX", &files, &server, &mut report).unwrap();
+
+        assert_eq!(report.stale_generations, 0);
+        assert_eq!(report.mismatched, 1);
+        assert!(!inbox.join("2.eml").exists());
+        assert!(dir
+            .join(verify::QUARANTINE_DIR)
+            .join("me_example.com")
+            .join("INBOX")
+            .join("2.eml")
+            .exists());
+        assert_eq!(
+            db.get_fetched_uids("me@example.com", "INBOX", 9).unwrap(),
+            [1, 3]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
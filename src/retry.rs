@@ -0,0 +1,288 @@
+use crate::config::RetryPolicy;
+use anyhow::Result;
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+use tracing::warn;
+
+/// What kind of failure an error is, deciding whether the operation is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The server rejected the credentials
+    Auth,
+    /// The connection could not be opened, was dropped or timed out
+    Network,
+    /// The server answered NO or BAD, e.g. for a mailbox that can't be selected
+    ServerNo,
+    /// The server sent a response that could not be parsed
+    Parse,
+    /// Anything else, e.g. a local file system or database error
+    Other,
+}
+
+impl ErrorClass {
+    /// Classifies an error by the first error in its chain that is recognized.
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<AuthError>() {
+                return ErrorClass::Auth;
+            }
+            if let Some(error) = cause.downcast_ref::<imap::Error>() {
+                return match error {
+                    imap::Error::Io(error) => io_class(error),
+                    imap::Error::ConnectionLost => ErrorClass::Network,
+                    imap::Error::No(_) | imap::Error::Bad(_) => ErrorClass::ServerNo,
+                    imap::Error::Parse(_) | imap::Error::Validate(_) => ErrorClass::Parse,
+                    _ => ErrorClass::Other,
+                };
+            }
+            if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+                return io_class(error);
+            }
+            if let Some(error) = cause.downcast_ref::<ureq::Error>() {
+                return match error {
+                    ureq::Error::Transport(_) => ErrorClass::Network,
+                    ureq::Error::Status(..) => ErrorClass::Other,
+                };
+            }
+        }
+        ErrorClass::Other
+    }
+
+    /// Only network errors can go away by themselves.
+    pub fn is_transient(self) -> bool {
+        self == ErrorClass::Network
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Auth => "auth",
+            ErrorClass::Network => "network",
+            ErrorClass::ServerNo => "server_no",
+            ErrorClass::Parse => "parse",
+            ErrorClass::Other => "other",
+        }
+    }
+}
+
+/// I/O errors are network errors, except for those a local file (e.g. a CA file) fails with.
+fn io_class(error: &std::io::Error) -> ErrorClass {
+    use std::io::ErrorKind;
+    match error.kind() {
+        ErrorKind::NotFound
+        | ErrorKind::PermissionDenied
+        | ErrorKind::AlreadyExists
+        | ErrorKind::InvalidInput
+        | ErrorKind::InvalidData
+        | ErrorKind::Unsupported
+        | ErrorKind::OutOfMemory => ErrorClass::Other,
+        _ => ErrorClass::Network,
+    }
+}
+
+/// The server refused to authenticate the account.
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Marks an error an operation still failed with after its last attempt, so an enclosing
+/// retry (e.g. a login around a connect) gives up too instead of multiplying the attempts.
+#[derive(Debug)]
+struct GaveUp {
+    operation: &'static str,
+    attempts: u32,
+}
+
+impl fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed after {} attempt(s)",
+            self.operation, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for GaveUp {}
+
+/// Whether another attempt of a failed operation can succeed.
+pub fn is_retriable(error: &anyhow::Error) -> bool {
+    ErrorClass::of(error).is_transient() && error.downcast_ref::<GaveUp>().is_none()
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't transient, or the
+/// attempts of `policy` are used up, sleeping with exponential backoff in between. The
+/// operation gets the number of the attempt (starting at 1), so it can reconnect first when
+/// it is a retry. Blocking, call from blocking tasks.
+pub fn retry<T>(
+    policy: &RetryPolicy,
+    operation: &'static str,
+    mut f: impl FnMut(u32) -> Result<T>,
+) -> Result<T> {
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let error = match f(attempt) {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        if !is_retriable(&error) {
+            return Err(error);
+        }
+        if attempt >= max_attempts {
+            return Err(error.context(GaveUp {
+                operation,
+                attempts: attempt,
+            }));
+        }
+
+        let delay = backoff(policy, attempt);
+        warn!(
+            operation,
+            attempt,
+            max_attempts,
+            retry_in_ms = delay.as_millis() as u64,
+            class = ErrorClass::of(&error).as_str(),
+            error = %format!("{:#}", error),
+            "Operation failed, retrying"
+        );
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// The delay after the given failed attempt: doubled on each attempt and capped, then
+/// randomized between half and all of it so reconnecting clients don't retry in lockstep.
+fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let exponential = policy
+        .initial_backoff_ms
+        .saturating_mul(1u64 << (attempt - 1).min(32));
+    let delay = exponential.min(policy.max_backoff_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(delay / 2..=delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 2,
+        }
+    }
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        io::Error::new(kind, "test").into()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_with_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+        };
+        for (attempt, full) in [(1, 1000), (2, 2000), (3, 4000), (5, 16_000), (6, 30_000)] {
+            for _ in 0..20 {
+                let delay = backoff(&policy, attempt).as_millis() as u64;
+                assert!(
+                    (full / 2..=full).contains(&delay),
+                    "attempt {attempt}: {delay}ms"
+                );
+            }
+        }
+        // Large attempt numbers don't overflow
+        assert!(backoff(&policy, 100) <= Duration::from_millis(30_000));
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(
+            ErrorClass::of(&io_error(io::ErrorKind::ConnectionReset)),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            ErrorClass::of(&io_error(io::ErrorKind::TimedOut)),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            ErrorClass::of(&io_error(io::ErrorKind::NotFound)),
+            ErrorClass::Other
+        );
+        assert_eq!(
+            ErrorClass::of(&imap::Error::ConnectionLost.into()),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            ErrorClass::of(&imap::Error::No("no such mailbox".to_string()).into()),
+            ErrorClass::ServerNo
+        );
+        assert_eq!(
+            ErrorClass::of(&AuthError("rejected".to_string()).into()),
+            ErrorClass::Auth
+        );
+        assert_eq!(
+            ErrorClass::of(&anyhow::anyhow!("something else")),
+            ErrorClass::Other
+        );
+    }
+
+    #[test]
+    fn classifies_by_the_cause_under_context() {
+        let error = io_error(io::ErrorKind::BrokenPipe).context("Failed to fetch batch");
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Network);
+        assert!(is_retriable(&error));
+    }
+
+    #[test]
+    fn retries_transient_errors_until_success() {
+        let mut attempts = Vec::new();
+        let result = retry(&policy(4), "test", |attempt| {
+            attempts.push(attempt);
+            if attempt < 3 {
+                Err(io_error(io::ErrorKind::ConnectionReset))
+            } else {
+                Ok(attempt)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, [1, 2, 3]);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let mut attempts = 0;
+        let result: Result<()> = retry(&policy(4), "test", |_| {
+            attempts += 1;
+            Err(AuthError("rejected".to_string()).into())
+        });
+        assert_eq!(ErrorClass::of(&result.unwrap_err()), ErrorClass::Auth);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts_without_multiplying_outer_retries() {
+        let mut attempts = 0;
+        let result: Result<()> = retry(&policy(3), "login", |_| {
+            retry(&policy(2), "connect", |_| {
+                attempts += 1;
+                Err(io_error(io::ErrorKind::ConnectionRefused))
+            })
+        });
+        let error = result.unwrap_err();
+        assert_eq!(attempts, 2);
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Network);
+        assert!(!is_retriable(&error));
+        assert!(format!("{:#}", error).contains("connect failed after 2 attempt(s)"));
+    }
+}
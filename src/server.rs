@@ -1,26 +1,98 @@
 use crate::config::AccountConfig;
-use crate::database::Database;
+use crate::control::{ControlState, FetchControl};
+use crate::database::{Database, FetchTrigger};
+use crate::events::EventBus;
+use crate::fetch_scope::FetchScope;
 use crate::fetcher::fetch_all_accounts;
+use crate::idle::{self, IdleWakeup};
+use crate::metrics;
+use crate::verify::{self, VerifyReport};
 use anyhow::Result;
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{Html, Json},
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, Json,
+    },
     routing::{get, post},
     Router,
 };
-use serde::Serialize;
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+/// How often changes reported by IDLE while another job runs are tried again.
+const IDLE_FETCH_RETRY: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<Database>,
     pub config: Arc<Vec<AccountConfig>>,
     pub output_dir: Arc<PathBuf>,
-    pub fetch_task: Arc<Mutex<Option<tokio::task::JoinHandle<Result<usize>>>>>,
+    pub fetch_task: Arc<Mutex<Option<FetchTask>>>,
+    /// Fetches of the mailboxes IDLE watchers report changes in run here, next to
+    /// `fetch_task`, so they neither wait for a long run nor keep one from starting.
+    pub idle_fetch_task: Arc<Mutex<Option<FetchTask>>>,
+    pub verify_job: Arc<Mutex<Option<VerifyJob>>>,
+    pub events: EventBus,
     pub fetch_interval_seconds: Option<u64>,
+    pub idle_keepalive_seconds: u64,
+    pub max_concurrent_accounts: usize,
+}
+
+/// A fetch started from the dashboard, periodic timer, startup or IDLE.
+pub struct FetchTask {
+    handle: tokio::task::JoinHandle<Result<usize>>,
+    control: FetchControl,
+}
+
+impl FetchTask {
+    fn is_running(&self) -> bool {
+        !self.handle.is_finished()
+    }
+}
+
+/// The latest archive verification started through `POST /api/verify`.
+#[derive(Clone, Serialize)]
+pub struct VerifyJob {
+    repair: bool,
+    started_at: String,
+    completed_at: Option<String>,
+    report: Option<VerifyReport>,
+    error: Option<String>,
+    /// Whether a failed-only fetch was started for the messages a repair scheduled. If a
+    /// fetch was already running, the next one picks them up.
+    refetch_started: bool,
+}
+
+impl VerifyJob {
+    fn is_running(&self) -> bool {
+        self.completed_at.is_none()
+    }
+}
+
+/// The job that kept a fetch or verification from starting. Fetches and verifications don't
+/// run at the same time: a fetch keeps the files of its current batch on disk without rows
+/// until the batch is committed, which a verification would take for orphans.
+enum Busy {
+    Fetch,
+    Verify,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct VerifyRequest {
+    #[serde(default)]
+    repair: bool,
 }
 
 #[derive(Serialize)]
@@ -42,7 +114,9 @@ struct StatsResponse {
     accounts: Vec<ServerInfo>,
     total_emails: i64,
     total_storage_bytes: i64,
+    total_deleted_on_server: i64,
     per_account_stats: Vec<AccountStats>,
+    uid_validity_events: Vec<UidValidityEventInfo>,
 }
 
 #[derive(Serialize)]
@@ -52,20 +126,135 @@ struct AccountStats {
     email_count: i64,
     storage_bytes: i64,
     last_fetch: Option<String>,
+    /// Archived messages that no longer exist on the server
+    deleted_on_server: i64,
+}
+
+#[derive(Serialize)]
+struct UidValidityEventInfo {
+    account_email: String,
+    mailbox: String,
+    old_uid_validity: u32,
+    new_uid_validity: u32,
+    archived_messages: i64,
+    archive_path: String,
+    detected_at: Option<String>,
 }
 
 #[derive(Serialize)]
 struct FetchStatusResponse {
     is_running: bool,
+    is_paused: bool,
     started_at: Option<String>,
     completed_at: Option<String>,
     messages_fetched: i64,
 }
 
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default = "default_history_page")]
+    page: i64,
+    #[serde(default = "default_history_per_page")]
+    per_page: i64,
+}
+
+fn default_history_page() -> i64 {
+    1
+}
+
+fn default_history_per_page() -> i64 {
+    20
+}
+
+#[derive(Serialize)]
+struct FetchHistoryResponse {
+    runs: Vec<FetchRunInfo>,
+    page: i64,
+    per_page: i64,
+    total_runs: i64,
+}
+
+#[derive(Serialize)]
+struct FetchRunInfo {
+    id: i64,
+    account_email: Option<String>,
+    mailbox: Option<String>,
+    triggered_by: Option<String>,
+    started_at: Option<String>,
+    completed_at: Option<String>,
+    status: String,
+    messages_fetched: i64,
+    messages_failed: i64,
+    bytes_fetched: i64,
+    mailboxes: Vec<MailboxFetchInfo>,
+}
+
+#[derive(Serialize)]
+struct MailboxFetchInfo {
+    account_email: String,
+    mailbox: Option<String>,
+    started_at: Option<String>,
+    completed_at: Option<String>,
+    status: String,
+    messages_fetched: i64,
+    messages_failed: i64,
+    bytes_fetched: i64,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FailuresQuery {
+    #[serde(default = "default_history_page")]
+    page: i64,
+    #[serde(default = "default_history_per_page")]
+    per_page: i64,
+    /// Also list messages marked as skipped
+    #[serde(default)]
+    include_skipped: bool,
+}
+
+#[derive(Serialize)]
+struct FailuresResponse {
+    failures: Vec<FetchFailureInfo>,
+    page: i64,
+    per_page: i64,
+    total_failures: i64,
+}
+
+#[derive(Serialize)]
+struct FetchFailureInfo {
+    id: i64,
+    account_email: String,
+    mailbox: String,
+    uid_validity: u32,
+    uid: u32,
+    error_class: String,
+    error: String,
+    attempts: i64,
+    first_failed_at: Option<String>,
+    last_failed_at: Option<String>,
+    skipped_at: Option<String>,
+}
+
 async fn dashboard_handler() -> Html<&'static str> {
     Html(include_str!("../assets/dashboard.html"))
 }
 
+/// Prometheus scrape endpoint.
+async fn metrics_handler(
+    State(state): State<AppState>,
+) -> Result<([(header::HeaderName, &'static str); 1], String), StatusCode> {
+    let body =
+        metrics::render(&state.db, &state.config).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    ))
+}
+
 async fn accounts_handler(State(state): State<AppState>) -> Json<Vec<ServerInfo>> {
     // Group accounts by server
     use std::collections::HashMap;
@@ -107,6 +296,24 @@ async fn stats_handler(State(state): State<AppState>) -> Result<Json<StatsRespon
             email_count: s.count,
             storage_bytes: s.total_size_bytes,
             last_fetch: s.last_fetch.map(|dt| dt.to_rfc3339()),
+            deleted_on_server: s.deleted_on_server,
+        })
+        .collect();
+    let total_deleted_on_server = per_account_stats.iter().map(|s| s.deleted_on_server).sum();
+
+    let uid_validity_events: Vec<UidValidityEventInfo> = state
+        .db
+        .get_uid_validity_events(20)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|e| UidValidityEventInfo {
+            account_email: e.account_email,
+            mailbox: e.mailbox,
+            old_uid_validity: e.old_uid_validity,
+            new_uid_validity: e.new_uid_validity,
+            archived_messages: e.archived_messages,
+            archive_path: e.archive_path,
+            detected_at: e.detected_at.map(|dt| dt.to_rfc3339()),
         })
         .collect();
 
@@ -133,34 +340,58 @@ async fn stats_handler(State(state): State<AppState>) -> Result<Json<StatsRespon
         accounts: servers.into_values().collect(),
         total_emails,
         total_storage_bytes,
+        total_deleted_on_server,
         per_account_stats,
+        uid_validity_events,
     }))
 }
 
+/// Starts a fetch. An optional JSON body (`FetchScope`) limits it to one account, matching
+/// mailboxes and/or a date range.
 async fn fetch_handler(
     State(state): State<AppState>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Check if a fetch is already running
-    let mut task_handle = state.fetch_task.lock().await;
-    if task_handle.is_some() {
-        return Ok(Json(serde_json::json!({
-            "status": "already_running",
-            "message": "A fetch operation is already in progress"
-        })));
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let invalid = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "status": "invalid_request",
+                "message": message
+            })),
+        )
+    };
+    let scope = if body.iter().all(u8::is_ascii_whitespace) {
+        FetchScope::default()
+    } else {
+        serde_json::from_slice::<FetchScope>(&body)
+            .map_err(|e| invalid(format!("Invalid fetch parameters: {}", e)))?
+    };
+    scope
+        .validate()
+        .and_then(|()| scope.select_accounts(&state.config))
+        .map_err(|e| invalid(format!("{:#}", e)))?;
+
+    let message = format!("Fetch operation started ({})", scope);
+    match trigger_fetch(&state, scope, FetchTrigger::Dashboard).await {
+        Ok(()) => {}
+        Err(Busy::Fetch) => {
+            return Ok(Json(serde_json::json!({
+                "status": "already_running",
+                "message": "A fetch operation is already in progress"
+            })));
+        }
+        Err(Busy::Verify) => {
+            return Ok(Json(serde_json::json!({
+                "status": "busy",
+                "message": "A verification is in progress, fetch once it is done"
+            })));
+        }
     }
 
-    let accounts = state.config.clone();
-    let output_dir = state.output_dir.clone();
-    let db = Arc::clone(&state.db);
-
-    // Spawn fetch task - fetch all mailboxes automatically
-    let handle = tokio::spawn(async move { fetch_all_accounts(&accounts, &output_dir, &db).await });
-
-    *task_handle = Some(handle);
-
     Ok(Json(serde_json::json!({
         "status": "started",
-        "message": "Fetch operation started (all mailboxes will be fetched)"
+        "message": message
     })))
 }
 
@@ -170,8 +401,8 @@ async fn fetch_status_handler(
     // Check if task is still running
     let mut task_handle = state.fetch_task.lock().await;
 
-    if let Some(ref handle) = *task_handle {
-        if handle.is_finished() {
+    if let Some(ref task) = *task_handle {
+        if task.handle.is_finished() {
             // Task completed, clean up
             let _ = task_handle.take();
             let db_status = state
@@ -180,28 +411,18 @@ async fn fetch_status_handler(
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
             if let Some(status) = db_status {
-                // Get completed_at from database - we need to query it directly
-                let conn = state.db.conn.lock().unwrap();
-                let completed_at: Option<String> = conn
-                    .query_row(
-                        "SELECT completed_at FROM fetch_history ORDER BY started_at DESC LIMIT 1",
-                        [],
-                        |row| row.get::<_, Option<String>>(0),
-                    )
-                    .ok()
-                    .flatten();
-                drop(conn);
-
                 return Ok(Json(FetchStatusResponse {
                     is_running: false,
+                    is_paused: false,
                     started_at: status.started_at.map(|dt| dt.to_rfc3339()),
-                    completed_at,
+                    completed_at: status.completed_at.map(|dt| dt.to_rfc3339()),
                     messages_fetched: status.messages_fetched,
                 }));
             }
 
             return Ok(Json(FetchStatusResponse {
                 is_running: false,
+                is_paused: false,
                 started_at: None,
                 completed_at: None,
                 messages_fetched: 0,
@@ -216,6 +437,7 @@ async fn fetch_status_handler(
             if let Some(status) = db_status {
                 return Ok(Json(FetchStatusResponse {
                     is_running: true,
+                    is_paused: task.control.state() == ControlState::Paused,
                     started_at: status.started_at.map(|dt| dt.to_rfc3339()),
                     completed_at: None,
                     messages_fetched: status.messages_fetched,
@@ -231,27 +453,17 @@ async fn fetch_status_handler(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     if let Some(status) = db_status {
-        // Get completed_at from database
-        let conn = state.db.conn.lock().unwrap();
-        let completed_at: Option<String> = conn
-            .query_row(
-                "SELECT completed_at FROM fetch_history ORDER BY started_at DESC LIMIT 1",
-                [],
-                |row| row.get::<_, Option<String>>(0),
-            )
-            .ok()
-            .flatten();
-        drop(conn);
-
         Ok(Json(FetchStatusResponse {
             is_running: false,
+            is_paused: false,
             started_at: status.started_at.map(|dt| dt.to_rfc3339()),
-            completed_at,
+            completed_at: status.completed_at.map(|dt| dt.to_rfc3339()),
             messages_fetched: status.messages_fetched,
         }))
     } else {
         Ok(Json(FetchStatusResponse {
             is_running: false,
+            is_paused: false,
             started_at: None,
             completed_at: None,
             messages_fetched: 0,
@@ -259,6 +471,344 @@ async fn fetch_status_handler(
     }
 }
 
+/// The controls of the running fetches, the scheduled or manual one and the one started by
+/// IDLE, which are paused, resumed and cancelled together.
+fn running_fetches(tasks: [&Option<FetchTask>; 2]) -> Vec<&FetchControl> {
+    tasks
+        .into_iter()
+        .flatten()
+        .filter(|task| task.is_running())
+        .map(|task| &task.control)
+        .collect()
+}
+
+/// Cancels the running fetch. It stops before the next message; everything saved until then
+/// stays in the archive and the run is recorded as cancelled.
+async fn fetch_cancel_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let fetch_task = state.fetch_task.lock().await;
+    let idle_fetch_task = state.idle_fetch_task.lock().await;
+    let controls = running_fetches([&fetch_task, &idle_fetch_task]);
+    if controls.is_empty() {
+        return Ok(Json(serde_json::json!({
+            "status": "not_running",
+            "message": "No fetch operation is running"
+        })));
+    }
+
+    if controls.iter().filter(|control| control.cancel()).count() == 0 {
+        return Ok(Json(serde_json::json!({
+            "status": "already_cancelled",
+            "message": "The fetch operation is already being cancelled"
+        })));
+    }
+    Ok(Json(serde_json::json!({
+        "status": "cancelling",
+        "message": "The fetch operation stops after the current message"
+    })))
+}
+
+async fn fetch_pause_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let fetch_task = state.fetch_task.lock().await;
+    let idle_fetch_task = state.idle_fetch_task.lock().await;
+    let controls = running_fetches([&fetch_task, &idle_fetch_task]);
+    let paused = controls.iter().filter(|control| control.pause()).count();
+    match controls.len() {
+        0 => Ok(Json(serde_json::json!({
+            "status": "not_running",
+            "message": "No fetch operation is running"
+        }))),
+        _ if paused > 0 => Ok(Json(serde_json::json!({
+            "status": "paused",
+            "message": "The fetch operation pauses after the current message"
+        }))),
+        _ => Ok(Json(serde_json::json!({
+            "status": "not_pausable",
+            "message": "The fetch operation is already paused or being cancelled"
+        }))),
+    }
+}
+
+async fn fetch_resume_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let fetch_task = state.fetch_task.lock().await;
+    let idle_fetch_task = state.idle_fetch_task.lock().await;
+    let controls = running_fetches([&fetch_task, &idle_fetch_task]);
+    let resumed = controls.iter().filter(|control| control.resume()).count();
+    match controls.len() {
+        0 => Ok(Json(serde_json::json!({
+            "status": "not_running",
+            "message": "No fetch operation is running"
+        }))),
+        _ if resumed > 0 => Ok(Json(serde_json::json!({
+            "status": "resumed",
+            "message": "Fetch operation resumed"
+        }))),
+        _ => Ok(Json(serde_json::json!({
+            "status": "not_paused",
+            "message": "The fetch operation is not paused"
+        }))),
+    }
+}
+
+async fn fetch_history_handler(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<FetchHistoryResponse>, StatusCode> {
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, 100);
+    let (total_runs, runs) = state
+        .db
+        .get_fetch_history(per_page, (page - 1) * per_page)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let runs = runs
+        .into_iter()
+        .map(|run| FetchRunInfo {
+            id: run.id,
+            account_email: run.account_email,
+            mailbox: run.mailbox,
+            triggered_by: run.triggered_by,
+            started_at: run.started_at.map(|dt| dt.to_rfc3339()),
+            completed_at: run.completed_at.map(|dt| dt.to_rfc3339()),
+            status: run.status,
+            messages_fetched: run.messages_fetched,
+            messages_failed: run.messages_failed,
+            bytes_fetched: run.bytes_fetched,
+            mailboxes: run
+                .mailboxes
+                .into_iter()
+                .map(|m| MailboxFetchInfo {
+                    account_email: m.account_email,
+                    mailbox: m.mailbox,
+                    started_at: m.started_at.map(|dt| dt.to_rfc3339()),
+                    completed_at: m.completed_at.map(|dt| dt.to_rfc3339()),
+                    status: m.status,
+                    messages_fetched: m.messages_fetched,
+                    messages_failed: m.messages_failed,
+                    bytes_fetched: m.bytes_fetched,
+                    error: m.error,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(Json(FetchHistoryResponse {
+        runs,
+        page,
+        per_page,
+        total_runs,
+    }))
+}
+
+/// Messages that failed to download and haven't been saved since, most recently failed first.
+async fn failures_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FailuresQuery>,
+) -> Result<Json<FailuresResponse>, StatusCode> {
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, 100);
+    let (total_failures, failures) = state
+        .db
+        .get_fetch_failures(per_page, (page - 1) * per_page, query.include_skipped)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let failures = failures
+        .into_iter()
+        .map(|failure| FetchFailureInfo {
+            id: failure.id,
+            account_email: failure.account_email,
+            mailbox: failure.mailbox,
+            uid_validity: failure.uid_validity,
+            uid: failure.uid,
+            error_class: failure.error_class,
+            error: failure.error,
+            attempts: failure.attempts,
+            first_failed_at: failure.first_failed_at.map(|dt| dt.to_rfc3339()),
+            last_failed_at: failure.last_failed_at.map(|dt| dt.to_rfc3339()),
+            skipped_at: failure.skipped_at.map(|dt| dt.to_rfc3339()),
+        })
+        .collect();
+
+    Ok(Json(FailuresResponse {
+        failures,
+        page,
+        per_page,
+        total_failures,
+    }))
+}
+
+/// Marks a failed message as permanently skipped, so no fetch tries it again.
+async fn failure_skip_handler(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    set_failure_skipped(&state, id, true)
+}
+
+/// Makes a skipped message eligible for fetching again.
+async fn failure_unskip_handler(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    set_failure_skipped(&state, id, false)
+}
+
+fn set_failure_skipped(
+    state: &AppState,
+    id: i64,
+    skipped: bool,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let found = state
+        .db
+        .set_fetch_failure_skipped(id, skipped)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !found {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(if skipped {
+        serde_json::json!({
+            "status": "skipped",
+            "message": "The message will not be fetched again"
+        })
+    } else {
+        serde_json::json!({
+            "status": "unskipped",
+            "message": "The message will be retried by the next fetch"
+        })
+    }))
+}
+
+/// Starts checking the archive against the database in the background, see
+/// `verify::verify_archive`. With `{"repair": true}` the problems found are repaired and the
+/// messages to download again are fetched right away.
+async fn verify_handler(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let request = if body.iter().all(u8::is_ascii_whitespace) {
+        VerifyRequest::default()
+    } else {
+        serde_json::from_slice::<VerifyRequest>(&body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "status": "invalid_request",
+                    "message": format!("Invalid verify parameters: {}", e)
+                })),
+            )
+        })?
+    };
+
+    // Locked in the same order as in `trigger_idle_fetch`
+    let fetch_task = state.fetch_task.lock().await;
+    let idle_fetch_task = state.idle_fetch_task.lock().await;
+    if fetch_task.as_ref().is_some_and(FetchTask::is_running)
+        || idle_fetch_task.as_ref().is_some_and(FetchTask::is_running)
+    {
+        return Ok(Json(serde_json::json!({
+            "status": "busy",
+            "message": "A fetch operation is in progress, verify once it is done"
+        })));
+    }
+    let mut job = state.verify_job.lock().await;
+    if job.as_ref().is_some_and(VerifyJob::is_running) {
+        return Ok(Json(serde_json::json!({
+            "status": "already_running",
+            "message": "A verification is already in progress"
+        })));
+    }
+    *job = Some(VerifyJob {
+        repair: request.repair,
+        started_at: chrono::Utc::now().to_rfc3339(),
+        completed_at: None,
+        report: None,
+        error: None,
+        refetch_started: false,
+    });
+    drop(job);
+    drop(idle_fetch_task);
+    drop(fetch_task);
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let db = Arc::clone(&state.db);
+        let accounts = state.config.clone();
+        let output_dir = state.output_dir.clone();
+        let repair = request.repair;
+        let result = tokio::task::spawn_blocking(move || {
+            verify::verify_archive(&db, &accounts, &output_dir, repair)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+
+        let refetch_scheduled = result
+            .as_ref()
+            .is_ok_and(|report| report.refetch_scheduled > 0);
+        if let Some(job) = state.verify_job.lock().await.as_mut() {
+            job.completed_at = Some(chrono::Utc::now().to_rfc3339());
+            match result {
+                Ok(report) => job.report = Some(report),
+                Err(e) => job.error = Some(format!("{:#}", e)),
+            }
+        }
+
+        // Only once the job is done, so the fetch isn't refused because of it
+        if refetch_scheduled {
+            let scope = FetchScope {
+                failed_only: true,
+                ..FetchScope::default()
+            };
+            let refetch_started = trigger_fetch(&state, scope, FetchTrigger::Repair)
+                .await
+                .is_ok();
+            if let Some(job) = state.verify_job.lock().await.as_mut() {
+                job.refetch_started = refetch_started;
+            }
+        }
+    });
+
+    Ok(Json(serde_json::json!({
+        "status": "started",
+        "message": if request.repair {
+            "Verification and repair started"
+        } else {
+            "Verification started"
+        }
+    })))
+}
+
+/// The latest verification: still running, or its report.
+async fn verify_status_handler(State(state): State<AppState>) -> Json<Option<VerifyJob>> {
+    Json(state.verify_job.lock().await.clone())
+}
+
+/// Streams fetch progress as Server-Sent Events, one JSON `FetchEvent` per message. A client
+/// that falls behind misses the oldest events and is sent a `lagged` event instead.
+async fn events_handler(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.events.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default()
+                .json_data(&event)
+                .unwrap_or_else(|_| Event::default().comment("unserializable event")),
+            Err(RecvError::Lagged(skipped)) => Event::default()
+                .data(serde_json::json!({ "type": "lagged", "skipped": skipped }).to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), receiver))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 pub fn create_router(state: AppState) -> Router {
     Router::new()
         .route("/", get(dashboard_handler))
@@ -266,30 +816,169 @@ pub fn create_router(state: AppState) -> Router {
         .route("/api/stats", get(stats_handler))
         .route("/api/fetch", post(fetch_handler))
         .route("/api/fetch/status", get(fetch_status_handler))
+        .route("/api/fetch/cancel", post(fetch_cancel_handler))
+        .route("/api/fetch/pause", post(fetch_pause_handler))
+        .route("/api/fetch/resume", post(fetch_resume_handler))
+        .route("/api/fetch/history", get(fetch_history_handler))
+        .route("/api/failures", get(failures_handler))
+        .route("/api/failures/:id/skip", post(failure_skip_handler))
+        .route("/api/failures/:id/unskip", post(failure_unskip_handler))
+        .route(
+            "/api/verify",
+            get(verify_status_handler).post(verify_handler),
+        )
+        .route("/api/events", get(events_handler))
+        .route("/metrics", get(metrics_handler))
         .with_state(state)
 }
 
-async fn trigger_fetch(state: &AppState) {
+/// Spawns a fetch of `scope` unless a fetch or verification is already running. Fetches
+/// started by IDLE don't count, they run next to it.
+async fn trigger_fetch(
+    state: &AppState,
+    scope: FetchScope,
+    trigger: FetchTrigger,
+) -> Result<(), Busy> {
     let mut task_handle = state.fetch_task.lock().await;
-    if task_handle.is_some() {
-        return; // Already running
+    if task_handle.as_ref().is_some_and(FetchTask::is_running) {
+        return Err(Busy::Fetch);
+    }
+    if state
+        .verify_job
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(VerifyJob::is_running)
+    {
+        return Err(Busy::Verify);
+    }
+
+    *task_handle = Some(spawn_fetch(state, scope, trigger));
+    Ok(())
+}
+
+/// Spawns a fetch of the mailboxes in `scope` IDLE reported changes in, unless the previous
+/// one is still running, the other fetch is paused or a verification is running.
+async fn trigger_idle_fetch(state: &AppState, scope: FetchScope) -> Result<(), Busy> {
+    let fetch_task = state.fetch_task.lock().await;
+    if fetch_task
+        .as_ref()
+        .is_some_and(|task| task.is_running() && task.control.state() == ControlState::Paused)
+    {
+        return Err(Busy::Fetch);
     }
+    let mut idle_fetch_task = state.idle_fetch_task.lock().await;
+    if idle_fetch_task.as_ref().is_some_and(FetchTask::is_running) {
+        return Err(Busy::Fetch);
+    }
+    if state
+        .verify_job
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(VerifyJob::is_running)
+    {
+        return Err(Busy::Verify);
+    }
+
+    *idle_fetch_task = Some(spawn_fetch(state, scope, FetchTrigger::Idle));
+    Ok(())
+}
 
+fn spawn_fetch(state: &AppState, scope: FetchScope, trigger: FetchTrigger) -> FetchTask {
     let accounts = state.config.clone();
     let output_dir = state.output_dir.clone();
     let db = Arc::clone(&state.db);
+    let max_concurrent_accounts = state.max_concurrent_accounts;
+    let events = state.events.clone();
+    let control = FetchControl::default();
+    let task_control = control.clone();
+
+    let handle = tokio::spawn(async move {
+        fetch_all_accounts(
+            &accounts,
+            &output_dir,
+            &db,
+            max_concurrent_accounts,
+            &scope,
+            trigger,
+            &events,
+            task_control,
+        )
+        .await
+    });
+
+    FetchTask { handle, control }
+}
 
-    // Spawn fetch task - fetch all mailboxes automatically
-    let handle = tokio::spawn(async move { fetch_all_accounts(&accounts, &output_dir, &db).await });
+/// Fetches the mailboxes IDLE watchers report changes in, through `trigger_idle_fetch` so
+/// they can be paused and cancelled and count against the connection limits. A mailbox a
+/// scheduled or manual fetch is syncing at the same time is fetched once that is done with
+/// it. Changes reported while the previous IDLE fetch or a verification is running are
+/// collected and fetched once it is done, one fetch per account.
+async fn run_idle_fetches(state: AppState, mut wakeups: mpsc::UnboundedReceiver<IdleWakeup>) {
+    let mut pending: BTreeSet<IdleWakeup> = BTreeSet::new();
+    loop {
+        if pending.is_empty() {
+            match wakeups.recv().await {
+                Some(wakeup) => pending.insert(wakeup),
+                None => return,
+            };
+        } else {
+            tokio::select! {
+                wakeup = wakeups.recv() => match wakeup {
+                    Some(wakeup) => pending.insert(wakeup),
+                    None => return,
+                },
+                _ = tokio::time::sleep(IDLE_FETCH_RETRY) => false,
+            };
+        }
+        while let Ok(wakeup) = wakeups.try_recv() {
+            pending.insert(wakeup);
+        }
 
-    *task_handle = Some(handle);
+        let Some(account) = pending.first().map(|wakeup| wakeup.account.clone()) else {
+            continue;
+        };
+        let mailboxes: Vec<IdleWakeup> = pending
+            .iter()
+            .filter(|wakeup| wakeup.account == account)
+            .cloned()
+            .collect();
+        let scope = FetchScope {
+            account: Some(account),
+            mailbox: Some(idle::exact_mailbox_pattern(
+                mailboxes.iter().map(|wakeup| wakeup.mailbox.as_str()),
+            )),
+            ..FetchScope::default()
+        };
+        if trigger_idle_fetch(&state, scope).await.is_ok() {
+            info!(
+                account = %mailboxes[0].account,
+                mailboxes = mailboxes.len(),
+                "Fetching mailboxes changed according to IDLE"
+            );
+            for wakeup in &mailboxes {
+                pending.remove(wakeup);
+            }
+        }
+    }
 }
 
 pub async fn start_server(state: AppState, port: u16, fetch_on_startup: bool) -> Result<()> {
+    // Runs still marked as running were cut short when the server last stopped
+    let interrupted = state.db.interrupt_unfinished_runs()?;
+    if interrupted > 0 {
+        warn!(
+            runs = interrupted,
+            "Marked unfinished fetch runs as interrupted"
+        );
+    }
+
     // Trigger fetch on startup if configured
     if fetch_on_startup {
-        println!("Starting initial fetch on startup...");
-        trigger_fetch(&state).await;
+        info!("Starting initial fetch on startup");
+        let _ = trigger_fetch(&state, FetchScope::default(), FetchTrigger::Startup).await;
     }
 
     // Start periodic fetch task if interval is configured
@@ -303,16 +992,30 @@ pub async fn start_server(state: AppState, port: u16, fetch_on_startup: bool) ->
 
             loop {
                 interval.tick().await;
-                println!("Periodic fetch triggered (interval: {}s)", interval_seconds);
-                trigger_fetch(&state_clone).await;
+                info!(interval_seconds, "Periodic fetch triggered");
+                if trigger_fetch(&state_clone, FetchScope::default(), FetchTrigger::Schedule)
+                    .await
+                    .is_err()
+                {
+                    info!("Another fetch or a verification is running, skipping this one");
+                }
             }
         });
-        println!("Periodic fetch enabled: every {} seconds", interval_seconds);
+        info!(interval_seconds, "Periodic fetch enabled");
     }
 
+    // Start IDLE watchers for accounts that configure `idle_mailboxes`
+    let (wakeups, wakeup_receiver) = mpsc::unbounded_channel();
+    idle::spawn_idle_watchers(
+        state.config.clone(),
+        Duration::from_secs(state.idle_keepalive_seconds),
+        wakeups,
+    );
+    tokio::spawn(run_idle_fetches(state.clone(), wakeup_receiver));
+
     let app = create_router(state);
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    println!("🚀 Courrier dashboard running on http://0.0.0.0:{}", port);
+    info!("Courrier dashboard running on http://0.0.0.0:{}", port);
     axum::serve(listener, app).await?;
     Ok(())
 }
@@ -0,0 +1,558 @@
+use crate::atomic_file;
+use crate::config::AccountConfig;
+use crate::database::{ArchivedMessage, Database, FetchedMessage, MessageAttributes};
+use crate::fetcher::{GMAIL_STORE_DIR, UID_VALIDITY_DIR_PREFIX};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Directory under `email_storage_path` damaged files are moved to by a repair, keeping their
+/// path relative to the storage path.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Temporary files younger than this may still be written by a running fetch.
+const TEMP_FILE_MIN_AGE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueKind {
+    /// A row points at a file that doesn't exist
+    Missing,
+    /// The file is shorter than the recorded size
+    Truncated,
+    /// The file has another size or checksum than recorded, or isn't an RFC 5322 message
+    Corrupted,
+    /// A message or temporary file no row points at
+    Orphaned,
+}
+
+/// What a repair did about an issue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repair {
+    /// The rows were removed and the messages recorded as failed, for the next fetch
+    Refetch,
+    /// A row was added for the orphaned file
+    Reindexed,
+    /// The leftover temporary file was deleted
+    Deleted,
+    /// The messages are no longer on the server (or belong to an old UIDVALIDITY
+    /// generation), or the orphan's mailbox can't be told from its path
+    Unrepairable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub path: String,
+    pub detail: String,
+    /// Rows pointing at the file, 0 for orphans
+    pub messages: usize,
+    pub repair: Option<Repair>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub messages_checked: usize,
+    pub files_checked: usize,
+    pub missing: usize,
+    pub truncated: usize,
+    pub corrupted: usize,
+    pub orphaned: usize,
+    /// Messages recorded as failed so the next fetch downloads them again
+    pub refetch_scheduled: usize,
+    pub reindexed: usize,
+    pub quarantined: usize,
+    pub temp_files_deleted: usize,
+    pub unrepairable: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    /// Problems that are still there: all of them without a repair, otherwise those the
+    /// repair couldn't fix.
+    pub fn unresolved(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| {
+                issue
+                    .repair
+                    .is_none_or(|repair| repair == Repair::Unrepairable)
+            })
+            .count()
+    }
+
+    fn add(&mut self, kind: IssueKind, path: &str, detail: String, messages: usize) {
+        match kind {
+            IssueKind::Missing => self.missing += 1,
+            IssueKind::Truncated => self.truncated += 1,
+            IssueKind::Corrupted => self.corrupted += 1,
+            IssueKind::Orphaned => self.orphaned += 1,
+        }
+        warn!(kind = ?kind, path, detail = %detail, "Archive problem");
+        self.issues.push(Issue {
+            kind,
+            path: path.to_string(),
+            detail,
+            messages,
+            repair: None,
+        });
+    }
+
+    fn repaired(&mut self, repair: Repair) {
+        if repair == Repair::Unrepairable {
+            self.unrepairable += 1;
+        }
+        if let Some(issue) = self.issues.last_mut() {
+            issue.repair = Some(repair);
+        }
+    }
+}
+
+/// Checks every `fetched_emails` row against its file: it has to exist, have the recorded
+/// size and SHA-256 and look like an RFC 5322 message. Then looks for `.eml` files under
+/// `output_dir` no row points at, and temporary files left by an interrupted write.
+///
+/// With `repair`, the rows of missing or damaged files are removed and their messages
+/// recorded as failed, so a failed-only fetch downloads them again (damaged files are moved
+/// to `.quarantine/` first). Orphans in a mailbox directory are re-indexed and old temporary
+/// files deleted. Blocking.
+pub fn verify_archive(
+    db: &Database,
+    accounts: &[AccountConfig],
+    output_dir: &Path,
+    repair: bool,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut generations = Generations::new(db);
+
+    let messages = db.get_archived_messages()?;
+    report.messages_checked = messages.len();
+    let mut referenced: HashSet<PathBuf> = db
+        .get_gmail_file_paths()?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+
+    for rows in messages.chunk_by(|a, b| a.file_path == b.file_path) {
+        let file_path = &rows[0].file_path;
+        referenced.insert(PathBuf::from(file_path));
+        report.files_checked += 1;
+
+        let Some((kind, detail)) = check_file(&rows[0]) else {
+            continue;
+        };
+        report.add(kind, file_path, detail, rows.len());
+        if repair {
+            let outcome = schedule_refetch(db, &mut generations, output_dir, rows, kind)?;
+            if outcome == Repair::Refetch {
+                report.refetch_scheduled += rows.len();
+                if kind != IssueKind::Missing {
+                    report.quarantined += 1;
+                }
+            }
+            report.repaired(outcome);
+        }
+    }
+
+    let mut files = Vec::new();
+    collect_files(output_dir, &output_dir.join(QUARANTINE_DIR), &mut files)?;
+    for file in files {
+        if referenced.contains(&file) {
+            continue;
+        }
+        let path = file.to_string_lossy().to_string();
+        if atomic_file::is_temp_file(&file) {
+            // A younger one may belong to a fetch that is still running
+            if !is_stale(&file)? {
+                continue;
+            }
+            report.add(
+                IssueKind::Orphaned,
+                &path,
+                "Temporary file of an interrupted write".to_string(),
+                0,
+            );
+            if repair {
+                fs::remove_file(&file)?;
+                report.temp_files_deleted += 1;
+                report.repaired(Repair::Deleted);
+            }
+            continue;
+        }
+        if file.extension().is_none_or(|ext| ext != "eml") {
+            continue;
+        }
+
+        report.add(
+            IssueKind::Orphaned,
+            &path,
+            "No database row points at this file".to_string(),
+            0,
+        );
+        if repair {
+            if reindex(db, &mut generations, accounts, output_dir, &file)? {
+                report.reindexed += 1;
+                report.repaired(Repair::Reindexed);
+            } else {
+                report.repaired(Repair::Unrepairable);
+            }
+        }
+    }
+
+    info!(
+        messages = report.messages_checked,
+        files = report.files_checked,
+        missing = report.missing,
+        truncated = report.truncated,
+        corrupted = report.corrupted,
+        orphaned = report.orphaned,
+        "Verification done"
+    );
+    Ok(report)
+}
+
+/// The problem with a row's file, if any.
+fn check_file(message: &ArchivedMessage) -> Option<(IssueKind, String)> {
+    let body = match fs::read(&message.file_path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Some((IssueKind::Missing, "File not found".to_string()));
+        }
+        Err(e) => return Some((IssueKind::Corrupted, format!("Unreadable: {}", e))),
+    };
+
+    let size = body.len() as i64;
+    if size < message.size_bytes {
+        return Some((
+            IssueKind::Truncated,
+            format!("{} of {} bytes", size, message.size_bytes),
+        ));
+    }
+    if size != message.size_bytes {
+        return Some((
+            IssueKind::Corrupted,
+            format!("{} bytes, expected {}", size, message.size_bytes),
+        ));
+    }
+    if let Some(expected) = &message.sha256 {
+        let actual = crate::blob_store::sha256_hex(&body);
+        if &actual != expected {
+            return Some((
+                IssueKind::Corrupted,
+                format!("SHA-256 {}, expected {}", actual, expected),
+            ));
+        }
+    }
+    if let Err(e) = check_rfc5322(&body) {
+        return Some((IssueKind::Corrupted, e));
+    }
+    None
+}
+
+/// A cheap RFC 5322 sanity check: the message has to start with a header section of
+/// `Name: value` fields (folded lines allowed), ended by an empty line or the end of the
+/// message.
+fn check_rfc5322(body: &[u8]) -> std::result::Result<(), String> {
+    let mut fields = 0;
+    for (number, line) in body.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            if fields == 0 {
+                return Err("Message starts with a folded header line".to_string());
+            }
+            continue;
+        }
+        let name = line
+            .iter()
+            .position(|b| *b == b':')
+            .map(|colon| &line[..colon])
+            .filter(|name| !name.is_empty() && name.iter().all(|b| (33..=126).contains(b)));
+        if name.is_none() {
+            return Err(format!("Invalid header line {}", number + 1));
+        }
+        fields += 1;
+    }
+    if fields == 0 {
+        return Err("No header fields".to_string());
+    }
+    Ok(())
+}
+
+/// Removes the rows of a missing or damaged file and records their messages as failed, if
+/// the server still has them in the current UIDVALIDITY generation. A damaged file is
+/// quarantined, so the download doesn't find it in the way.
+fn schedule_refetch(
+    db: &Database,
+    generations: &mut Generations,
+    output_dir: &Path,
+    rows: &[ArchivedMessage],
+    kind: IssueKind,
+) -> Result<Repair> {
+    let mut refetchable = Vec::new();
+    for row in rows {
+        let current = generations.current(&row.account_email, &row.mailbox)?;
+        if !row.deleted_on_server && current == Some(row.uid_validity) {
+            refetchable.push(row);
+        }
+    }
+    if refetchable.is_empty() {
+        return Ok(Repair::Unrepairable);
+    }
+
+    let file_path = &rows[0].file_path;
+    if kind != IssueKind::Missing {
+        quarantine(output_dir, Path::new(file_path))?;
+    }
+    db.forget_archived_file(file_path)?;
+    let error = format!("{} in the archive (found by verify)", describe(kind));
+    for row in refetchable {
+        db.record_fetch_failures(
+            &row.account_email,
+            &row.mailbox,
+            row.uid_validity,
+            &[(row.uid, "other", &error)],
+        )?;
+    }
+    Ok(Repair::Refetch)
+}
+
+fn describe(kind: IssueKind) -> &'static str {
+    match kind {
+        IssueKind::Missing => "File missing",
+        IssueKind::Truncated => "File truncated",
+        IssueKind::Corrupted => "File corrupted",
+        IssueKind::Orphaned => "File orphaned",
+    }
+}
+
+/// Moves a file to the same relative path under `.quarantine/`.
+pub fn quarantine(output_dir: &Path, file: &Path) -> Result<()> {
+    let relative = file.strip_prefix(output_dir).unwrap_or(file);
+    let relative = relative.strip_prefix("/").unwrap_or(relative);
+    let target = output_dir.join(QUARANTINE_DIR).join(relative);
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::rename(file, &target)
+        .with_context(|| format!("Failed to quarantine {}", file.display()))?;
+    info!(path = %file.display(), to = %target.display(), "Quarantined damaged file");
+    Ok(())
+}
+
+/// Adds the row of an orphaned `<account>/<mailbox>/<uid>.eml` (or one in a
+/// `.uidvalidity-<n>` directory), unless its mailbox can't be told from the path, the file
+/// isn't a message or the UID already has a row. Returns whether a row was added.
+fn reindex(
+    db: &Database,
+    generations: &mut Generations,
+    accounts: &[AccountConfig],
+    output_dir: &Path,
+    file: &Path,
+) -> Result<bool> {
+    let Some(location) = MessageLocation::parse(accounts, output_dir, file) else {
+        return Ok(false);
+    };
+    let uid_validity = match location.uid_validity {
+        Some(uid_validity) => uid_validity,
+        None => match generations.current(&location.account_email, &location.mailbox)? {
+            Some(uid_validity) => uid_validity,
+            None => return Ok(false),
+        },
+    };
+    let existing = db.get_fetched_uids(&location.account_email, &location.mailbox, uid_validity)?;
+    if existing.contains(&location.uid) {
+        return Ok(false);
+    }
+
+    let body = fs::read(file)?;
+    if check_rfc5322(&body).is_err() {
+        return Ok(false);
+    }
+    let sha256 = crate::blob_store::sha256_hex(&body);
+    db.record_fetched_messages(
+        &location.account_email,
+        &location.mailbox,
+        uid_validity,
+        &[FetchedMessage {
+            uid: location.uid,
+            file_path: file,
+            size_bytes: body.len(),
+            sha256: Some(&sha256),
+            attributes: &MessageAttributes::default(),
+            gmail: None,
+        }],
+    )?;
+    info!(path = %file.display(), "Re-indexed orphaned file");
+    Ok(true)
+}
+
+/// Where a message file in a mailbox directory belongs, read from its path.
+pub struct MessageLocation {
+    pub account_email: String,
+    pub mailbox: String,
+    /// Set for files in a `.uidvalidity-<n>` directory, otherwise the mailbox's current one
+    pub uid_validity: Option<u32>,
+    pub uid: u32,
+}
+
+impl MessageLocation {
+    /// Parses `<account>/<mailbox...>/[.uidvalidity-<n>/]<uid>.eml` under `output_dir`, with
+    /// `<account>` the directory of a configured account. Gmail and blob store files aren't
+    /// named by UID and give `None`.
+    pub fn parse(accounts: &[AccountConfig], output_dir: &Path, file: &Path) -> Option<Self> {
+        let relative = file.strip_prefix(output_dir).ok()?;
+        let mut parts: Vec<String> = relative
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let file_name = parts.pop()?;
+        let uid: u32 = file_name.strip_suffix(".eml")?.parse().ok()?;
+
+        let account_dir = parts.first()?.clone();
+        let account = accounts
+            .iter()
+            .find(|account| account.email.replace("@", "_") == account_dir)?;
+        let mut mailbox_parts = &parts[1..];
+        let mut uid_validity = None;
+        if let Some(generation) = mailbox_parts
+            .last()
+            .and_then(|last| last.strip_prefix(UID_VALIDITY_DIR_PREFIX))
+        {
+            uid_validity = Some(generation.parse().ok()?);
+            mailbox_parts = &mailbox_parts[..mailbox_parts.len() - 1];
+        }
+        if mailbox_parts.is_empty() || mailbox_parts[0] == GMAIL_STORE_DIR {
+            return None;
+        }
+
+        Some(MessageLocation {
+            account_email: account.email.clone(),
+            mailbox: mailbox_parts.join("/"),
+            uid_validity,
+            uid,
+        })
+    }
+}
+
+/// The current UIDVALIDITY of each mailbox, looked up once.
+struct Generations<'a> {
+    db: &'a Database,
+    known: HashMap<(String, String), Option<u32>>,
+}
+
+impl<'a> Generations<'a> {
+    fn new(db: &'a Database) -> Self {
+        Generations {
+            db,
+            known: HashMap::new(),
+        }
+    }
+
+    fn current(&mut self, account_email: &str, mailbox: &str) -> Result<Option<u32>> {
+        let key = (account_email.to_string(), mailbox.to_string());
+        if let Some(uid_validity) = self.known.get(&key) {
+            return Ok(*uid_validity);
+        }
+        let uid_validity = self
+            .db
+            .get_mailbox_state(account_email, mailbox)?
+            .map(|state| state.uid_validity);
+        self.known.insert(key, uid_validity);
+        Ok(uid_validity)
+    }
+}
+
+/// Lists the files under `dir`, except those in `skip`. Symlinks aren't followed.
+pub fn collect_files(dir: &Path, skip: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if path != skip {
+                collect_files(&path, skip, files)?;
+            }
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a temporary file is too old to still be written by a running fetch. Its
+/// modification time is when it was last written to, the INTERNALDATE is only set once the
+/// file is renamed into place.
+fn is_stale(file: &Path) -> Result<bool> {
+    let modified = fs::metadata(file)?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    Ok(age >= TEMP_FILE_MIN_AGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(file: &str) -> Option<MessageLocation> {
+        let accounts = [AccountConfig::for_test("me@example.com")];
+        MessageLocation::parse(
+            &accounts,
+            Path::new("/archive"),
+            &Path::new("/archive").join(file),
+        )
+    }
+
+    #[test]
+    fn parses_current_generation() {
+        let location = parse("me_example.com/INBOX/42.eml").unwrap();
+        assert_eq!(location.account_email, "me@example.com");
+        assert_eq!(location.mailbox, "INBOX");
+        assert_eq!(location.uid_validity, None);
+        assert_eq!(location.uid, 42);
+    }
+
+    #[test]
+    fn parses_nested_mailbox_and_old_generation() {
+        let location = parse("me_example.com/Archive/2024/.uidvalidity-1234/7.eml").unwrap();
+        assert_eq!(location.mailbox, "Archive/2024");
+        assert_eq!(location.uid_validity, Some(1234));
+        assert_eq!(location.uid, 7);
+    }
+
+    #[test]
+    fn rejects_files_not_named_by_uid() {
+        // Gmail store, blob store, unknown account, no mailbox, not a message
+        assert!(parse("me_example.com/gmail-messages/1234567890.eml").is_none());
+        assert!(parse("blobs/ab/abcdef.eml").is_none());
+        assert!(parse("other_example.com/INBOX/1.eml").is_none());
+        assert!(parse("me_example.com/1.eml").is_none());
+        assert!(parse("me_example.com/INBOX/notes.txt").is_none());
+        assert!(parse("me_example.com/INBOX/.uidvalidity-x/1.eml").is_none());
+        assert!(parse("me_example.com/.uidvalidity-5/1.eml").is_none());
+    }
+
+    #[test]
+    fn checks_the_header_section() {
+        assert!(check_rfc5322(b"Subject: hi\r\nX-Long: a\r\n b\r\n\r\nbody").is_ok());
+        assert!(check_rfc5322(b"From: a@example.com\n\n").is_ok());
+        assert!(check_rfc5322(b"").is_err());
+        assert!(check_rfc5322(b"\r\nbody").is_err());
+        assert!(check_rfc5322(b" folded: first\r\n\r\n").is_err());
+        assert!(check_rfc5322(b"not a header\r\n\r\n").is_err());
+        assert!(check_rfc5322(b": no name\r\n\r\n").is_err());
+    }
+}